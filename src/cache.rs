@@ -0,0 +1,115 @@
+/*
+ * Copyright 2018 Intel Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// A map that holds at most `capacity` entries, evicting the least recently
+/// used entry to make room for a new one.
+pub struct LruCache<K, V> {
+    capacity: usize,
+    entries: HashMap<K, (V, u64)>,
+    // Keys ordered by when they were last used
+    recency: BTreeMap<u64, K>,
+    clock: u64,
+}
+
+impl<K: Clone + Eq + Hash, V> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let clock = self.tick();
+        let entry = self.entries.get_mut(key)?;
+        self.recency.remove(&entry.1);
+        self.recency.insert(clock, key.clone());
+        entry.1 = clock;
+        Some(&entry.0)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.remove(&key);
+        while self.entries.len() >= self.capacity {
+            let oldest = match self.recency.keys().next() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            if let Some(key) = self.recency.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+
+        let clock = self.tick();
+        self.recency.insert(clock, key.clone());
+        self.entries.insert(key, (value, clock));
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, last_used) = self.entries.remove(key)?;
+        self.recency.remove(&last_used);
+        Some(value)
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = LruCache::new(2);
+        cache.insert("a", 1);
+        cache.insert("b", 2);
+        assert_eq!(cache.get(&"a"), Some(&1));
+
+        cache.insert("c", 3);
+
+        assert_eq!(cache.get(&"b"), None);
+        assert_eq!(cache.get(&"a"), Some(&1));
+        assert_eq!(cache.get(&"c"), Some(&3));
+    }
+
+    #[test]
+    fn replacing_and_removing_keeps_size() {
+        let mut cache = LruCache::new(2);
+        cache.insert("a", 1);
+        cache.insert("a", 2);
+        cache.insert("b", 3);
+        assert_eq!(cache.get(&"a"), Some(&2));
+        assert_eq!(cache.get(&"b"), Some(&3));
+
+        assert_eq!(cache.remove(&"a"), Some(2));
+        assert_eq!(cache.remove(&"a"), None);
+        cache.insert("c", 4);
+        assert_eq!(cache.get(&"b"), Some(&3));
+    }
+}
@@ -0,0 +1,41 @@
+/*
+ * Copyright 2018 Intel Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+use std::time::{Instant, SystemTime};
+
+/// Source of time for the engine. Everything that measures time goes through
+/// a `Clock`, so that time can be simulated.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Wall-clock time, for timestamps shared with other nodes.
+    fn system_time(&self) -> SystemTime;
+}
+
+/// The real, monotonic system clock.
+#[derive(Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
@@ -15,19 +15,214 @@
  * ------------------------------------------------------------------------------
  */
 
-use std::fmt::{self, Write};
+use std::cmp;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::mpsc::{Receiver, RecvTimeoutError};
-use std::thread::sleep;
-use std::time;
-
-use rand;
-use rand::Rng;
+use std::sync::Arc;
+use std::time::{self, UNIX_EPOCH};
 
 use sawtooth_sdk::consensus::{engine::*, service::Service};
 
-const DEFAULT_WAIT_TIME: u64 = 0;
+use cache::LruCache;
+use clock::{Clock, SystemClock};
+use error::DevmodeError;
+use fork::{Fork, ForkComparison, ForkResolution, ForkResolver};
+use hex;
+use payload::{ConsensusPayload, PayloadV1, LEGACY_MAGIC};
+use peers::{PeerKey, PeerTable};
+use propagation::PropagationTracker;
+use reputation::{Misbehaviour, MisbehaviourAction, Reputation};
+use rotation::Rotation;
+use schedule::{duration_to_millis, wait_time_range, PublishingSchedule, ScheduleMode};
+use settings::DevmodeSettings;
+use signature::PayloadSigner;
+use validation::ValidationTracker;
+
+const BLOCK_CACHE_SIZE: usize = 1024;
+const SETTINGS_CACHE_SIZE: usize = 16;
 const NULL_BLOCK_IDENTIFIER: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0];
+// How long to wait before asking the validator again about a block that
+// wasn't ready to be summarized or finalized.
+const NOT_READY_RETRY_INTERVAL: time::Duration = time::Duration::from_secs(1);
+// How long to wait for peers to report receiving a published block before
+// summarizing how far it got.
+const PROPAGATION_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+pub const DEFAULT_LEADER_FALLBACK: time::Duration = time::Duration::from_secs(10);
+
+/// Progress of the block the engine is building on the current chain head.
+#[derive(Clone, Debug, PartialEq)]
+enum PublishState {
+    /// No block is in progress.
+    Idle,
+    /// A block is in progress; waiting for the timer to expire.
+    Initialized,
+    /// The timer has expired; waiting for the validator to summarize the
+    /// block.
+    WaitingToSummarize,
+    /// The block has been summarized; waiting for the validator to finalize
+    /// it with this consensus data.
+    WaitingToFinalize(Vec<u8>),
+    /// A block has been published at this height.
+    Published,
+}
+
+impl fmt::Display for PublishState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            PublishState::Idle => "Idle",
+            PublishState::Initialized => "Initialized",
+            PublishState::WaitingToSummarize => "WaitingToSummarize",
+            PublishState::WaitingToFinalize(_) => "WaitingToFinalize",
+            PublishState::Published => "Published",
+        })
+    }
+}
+
+/// What the engine keeps track of between updates. It only changes through
+/// the transition methods below, which refuse transitions that don't make
+/// sense from the current state and log the ones that happen.
+struct EngineState {
+    chain_head: Block,
+    publish: PublishState,
+    wait_time: time::Duration,
+    // When the publishing timer was last armed, and for how long.
+    timer_start: time::Instant,
+    timer_length: time::Duration,
+    // When the engine started building on the current chain head.
+    height_start: time::Instant,
+    // When to next ask the validator about a block that wasn't ready.
+    retry_at: time::Instant,
+}
+
+impl EngineState {
+    fn new(chain_head: Block, wait_time: time::Duration, now: time::Instant) -> Self {
+        EngineState {
+            chain_head,
+            publish: PublishState::Idle,
+            wait_time,
+            timer_start: now,
+            timer_length: wait_time,
+            height_start: now,
+            retry_at: now,
+        }
+    }
+
+    fn timer_expired(&self, now: time::Instant) -> bool {
+        now.duration_since(self.timer_start) > self.timer_length
+    }
+
+    // Whether the block is waiting on the validator and it's time to ask
+    // again.
+    fn should_retry(&self, now: time::Instant) -> bool {
+        self.is_waiting() && now >= self.retry_at
+    }
+
+    fn is_waiting(&self) -> bool {
+        matches!(
+            self.publish,
+            PublishState::WaitingToSummarize | PublishState::WaitingToFinalize(_)
+        )
+    }
+
+    /// A new block was started: Idle -> Initialized, arming the timer.
+    fn initialize(&mut self, now: time::Instant) -> Result<(), DevmodeError> {
+        self.check(self.publish == PublishState::Idle, "initialize a block")?;
+        self.arm_timer(self.wait_time, now);
+        self.transition(PublishState::Initialized);
+        Ok(())
+    }
+
+    /// The block in progress, if any, was abandoned: anything -> Idle. The
+    /// timer is re-armed so a new block is started once it expires.
+    fn cancel(&mut self, now: time::Instant) {
+        self.arm_timer(self.wait_time, now);
+        self.transition(PublishState::Idle);
+    }
+
+    /// The timer expired: Initialized -> WaitingToSummarize.
+    fn start_publishing(&mut self, now: time::Instant) -> Result<(), DevmodeError> {
+        self.check(self.publish == PublishState::Initialized, "publish a block")?;
+        self.retry_at = now;
+        self.transition(PublishState::WaitingToSummarize);
+        Ok(())
+    }
+
+    /// The validator wasn't ready; ask again after NOT_READY_RETRY_INTERVAL.
+    fn not_ready(&mut self, now: time::Instant) -> Result<(), DevmodeError> {
+        self.check(self.is_waiting(), "wait on the validator")?;
+        self.retry_at = now + NOT_READY_RETRY_INTERVAL;
+        Ok(())
+    }
+
+    /// The block is empty and should be skipped: WaitingToSummarize ->
+    /// Initialized, re-arming the timer. With a short wait time, the timer
+    /// waits NOT_READY_RETRY_INTERVAL instead, so skipping never asks the
+    /// validator more often than waiting on it would.
+    fn skip_empty(&mut self, now: time::Instant) -> Result<(), DevmodeError> {
+        self.check(
+            self.publish == PublishState::WaitingToSummarize,
+            "skip an empty block",
+        )?;
+        let length = cmp::max(self.wait_time, NOT_READY_RETRY_INTERVAL);
+        self.arm_timer(length, now);
+        self.transition(PublishState::Initialized);
+        Ok(())
+    }
+
+    /// The block was summarized: WaitingToSummarize -> WaitingToFinalize.
+    fn summarized(&mut self, consensus: Vec<u8>) -> Result<(), DevmodeError> {
+        self.check(
+            self.publish == PublishState::WaitingToSummarize,
+            "summarize a block",
+        )?;
+        self.transition(PublishState::WaitingToFinalize(consensus));
+        Ok(())
+    }
+
+    /// The block was finalized: WaitingToFinalize -> Published.
+    fn published(&mut self) -> Result<(), DevmodeError> {
+        self.check(
+            matches!(self.publish, PublishState::WaitingToFinalize(_)),
+            "finalize a block",
+        )?;
+        self.transition(PublishState::Published);
+        Ok(())
+    }
+
+    /// The validator committed a new chain head: anything -> Idle, with the
+    /// wait time for the new height.
+    fn commit(&mut self, wait_time: time::Duration, now: time::Instant) {
+        self.wait_time = wait_time;
+        self.height_start = now;
+        self.cancel(now);
+    }
+
+    fn arm_timer(&mut self, length: time::Duration, now: time::Instant) {
+        self.timer_start = now;
+        self.timer_length = length;
+    }
+
+    fn check(&self, allowed: bool, action: &str) -> Result<(), DevmodeError> {
+        if allowed {
+            Ok(())
+        } else {
+            Err(DevmodeError::InvalidState(format!(
+                "Cannot {} while {}",
+                action, self.publish
+            )))
+        }
+    }
+
+    fn transition(&mut self, to: PublishState) {
+        if self.publish != to {
+            debug!("Engine state: {} -> {}", self.publish, to);
+            self.publish = to;
+        }
+    }
+}
 
 #[derive(Default)]
 struct LogGuard {
@@ -38,6 +233,12 @@ struct LogGuard {
 pub struct DevmodeService {
     service: Box<dyn Service>,
     log_guard: LogGuard,
+    block_cache: LruCache<BlockId, Block>,
+    settings_cache: LruCache<BlockId, DevmodeSettings>,
+    // The settings most recently fetched, to fall back on if fetching fails.
+    last_settings: Option<DevmodeSettings>,
+    // The current chain head, if known; cleared when it is about to change.
+    chain_head_id: Option<BlockId>,
 }
 
 impl DevmodeService {
@@ -45,344 +246,668 @@ impl DevmodeService {
         DevmodeService {
             service,
             log_guard: LogGuard::default(),
+            block_cache: LruCache::new(BLOCK_CACHE_SIZE),
+            settings_cache: LruCache::new(SETTINGS_CACHE_SIZE),
+            last_settings: None,
+            chain_head_id: None,
         }
     }
 
-    fn get_chain_head(&mut self) -> Block {
+    fn cache_block(&mut self, block: Block) {
+        self.block_cache.insert(block.block_id.clone(), block);
+    }
+
+    fn forget_block(&mut self, block_id: &BlockId) {
+        self.block_cache.remove(block_id);
+    }
+
+    // Record that the validator has committed a new chain head.
+    fn chain_head_updated(&mut self, block_id: BlockId) {
+        self.chain_head_id = Some(block_id);
+    }
+
+    fn get_chain_head(&mut self) -> Result<Block, DevmodeError> {
+        if let Some(chain_head_id) = self.chain_head_id.clone() {
+            if let Some(chain_head) = self.block_cache.get(&chain_head_id) {
+                return Ok(chain_head.clone());
+            }
+        }
+
         debug!("Getting chain head");
-        self.service
+        let chain_head = self
+            .service
             .get_chain_head()
-            .expect("Failed to get chain head")
+            .map_err(|err| DevmodeError::from_service("get chain head", err))?;
+        self.chain_head_id = Some(chain_head.block_id.clone());
+        self.cache_block(chain_head.clone());
+        Ok(chain_head)
     }
 
     #[allow(clippy::ptr_arg)]
-    fn get_block(&mut self, block_id: &BlockId) -> Block {
-        debug!("Getting block {}", to_hex(&block_id));
-        self.service
-            .get_blocks(vec![block_id.clone()])
-            .expect("Failed to get block")
+    fn get_block(&mut self, block_id: &BlockId) -> Result<Block, DevmodeError> {
+        self.get_blocks(vec![block_id.clone()])?
             .remove(block_id)
-            .unwrap()
+            .ok_or_else(|| {
+                DevmodeError::UnknownBlock(format!(
+                    "Failed to get block: {} was not returned",
+                    hex::encode(block_id)
+                ))
+            })
+    }
+
+    // Get the given blocks, only asking the validator for the ones that
+    // aren't cached.
+    fn get_blocks(
+        &mut self,
+        block_ids: Vec<BlockId>,
+    ) -> Result<HashMap<BlockId, Block>, DevmodeError> {
+        let mut blocks = HashMap::new();
+        let mut missing = vec![];
+        for block_id in block_ids {
+            if let Some(block) = self.block_cache.get(&block_id) {
+                blocks.insert(block_id, block.clone());
+            } else if !missing.contains(&block_id) {
+                missing.push(block_id);
+            }
+        }
+
+        if !missing.is_empty() {
+            for block_id in &missing {
+                debug!("Getting block {}", hex::encode(block_id));
+            }
+            let fetched = self
+                .service
+                .get_blocks(missing)
+                .map_err(|err| DevmodeError::from_service("get blocks", err))?;
+            for (block_id, block) in fetched {
+                self.cache_block(block.clone());
+                blocks.insert(block_id, block);
+            }
+        }
+
+        Ok(blocks)
     }
 
-    fn initialize_block(&mut self) {
+    fn initialize_block(&mut self) -> Result<(), DevmodeError> {
         debug!("Initializing block");
         self.service
             .initialize_block(None)
-            .expect("Failed to initialize");
+            .map_err(|err| DevmodeError::from_service("initialize block", err))
     }
 
-    fn finalize_block(&mut self) -> BlockId {
-        debug!("Finalizing block");
-        let mut summary = self.service.summarize_block();
-        while let Err(Error::BlockNotReady) = summary {
-            if !self.log_guard.not_ready_to_summarize {
-                self.log_guard.not_ready_to_summarize = true;
-                debug!("Block not ready to summarize");
+    // Ask the validator to summarize the block in progress. Returns None if
+    // it isn't ready to be summarized yet.
+    fn summarize_block(&mut self) -> Result<Option<Vec<u8>>, DevmodeError> {
+        debug!("Summarizing block");
+        match self.service.summarize_block() {
+            Ok(summary) => {
+                self.log_guard.not_ready_to_summarize = false;
+                debug!("Block has been summarized successfully");
+                Ok(Some(summary))
             }
-            sleep(time::Duration::from_secs(1));
-            summary = self.service.summarize_block();
-        }
-        self.log_guard.not_ready_to_summarize = false;
-        let summary = summary.expect("Failed to summarize block");
-        debug!("Block has been summarized successfully");
-
-        let consensus: Vec<u8> = create_consensus(&summary);
-        let mut block_id = self.service.finalize_block(consensus.clone());
-        while let Err(Error::BlockNotReady) = block_id {
-            if !self.log_guard.not_ready_to_finalize {
-                self.log_guard.not_ready_to_finalize = true;
-                debug!("Block not ready to finalize");
+            Err(Error::BlockNotReady) => {
+                if !self.log_guard.not_ready_to_summarize {
+                    self.log_guard.not_ready_to_summarize = true;
+                    debug!("Block not ready to summarize");
+                }
+                Ok(None)
             }
-            sleep(time::Duration::from_secs(1));
-            block_id = self.service.finalize_block(consensus.clone());
+            Err(err) => Err(DevmodeError::from_service("summarize block", err)),
         }
-        self.log_guard.not_ready_to_finalize = false;
-        let block_id = block_id.expect("Failed to finalize block");
-        debug!(
-            "Block has been finalized successfully: {}",
-            to_hex(&block_id)
-        );
+    }
 
-        block_id
+    // Ask the validator to finalize the block in progress with the given
+    // consensus data. Returns None if it isn't ready to be finalized yet.
+    fn finalize_block(&mut self, consensus: Vec<u8>) -> Result<Option<BlockId>, DevmodeError> {
+        debug!("Finalizing block");
+        match self.service.finalize_block(consensus) {
+            Ok(block_id) => {
+                self.log_guard.not_ready_to_finalize = false;
+                debug!(
+                    "Block has been finalized successfully: {}",
+                    hex::encode(&block_id)
+                );
+                Ok(Some(block_id))
+            }
+            Err(Error::BlockNotReady) => {
+                if !self.log_guard.not_ready_to_finalize {
+                    self.log_guard.not_ready_to_finalize = true;
+                    debug!("Block not ready to finalize");
+                }
+                Ok(None)
+            }
+            Err(err) => Err(DevmodeError::from_service("finalize block", err)),
+        }
     }
 
-    fn check_block(&mut self, block_id: BlockId) {
-        debug!("Checking block {}", to_hex(&block_id));
+    fn check_block(&mut self, block_id: BlockId) -> Result<(), DevmodeError> {
+        debug!("Checking block {}", hex::encode(&block_id));
         self.service
             .check_blocks(vec![block_id])
-            .expect("Failed to check block");
+            .map_err(|err| DevmodeError::from_service("check block", err))
     }
 
-    fn fail_block(&mut self, block_id: BlockId) {
-        debug!("Failing block {}", to_hex(&block_id));
+    fn fail_block(&mut self, block_id: BlockId) -> Result<(), DevmodeError> {
+        debug!("Failing block {}", hex::encode(&block_id));
+        self.block_cache.remove(&block_id);
         self.service
             .fail_block(block_id)
-            .expect("Failed to fail block");
+            .map_err(|err| DevmodeError::from_service("fail block", err))
     }
 
-    fn ignore_block(&mut self, block_id: BlockId) {
-        debug!("Ignoring block {}", to_hex(&block_id));
+    fn ignore_block(&mut self, block_id: BlockId) -> Result<(), DevmodeError> {
+        debug!("Ignoring block {}", hex::encode(&block_id));
         self.service
             .ignore_block(block_id)
-            .expect("Failed to ignore block")
+            .map_err(|err| DevmodeError::from_service("ignore block", err))
     }
 
-    fn commit_block(&mut self, block_id: BlockId) {
-        debug!("Committing block {}", to_hex(&block_id));
+    fn commit_block(&mut self, block_id: BlockId) -> Result<(), DevmodeError> {
+        debug!("Committing block {}", hex::encode(&block_id));
+        self.chain_head_id = None;
         self.service
             .commit_block(block_id)
-            .expect("Failed to commit block");
+            .map_err(|err| DevmodeError::from_service("commit block", err))
     }
 
-    fn cancel_block(&mut self) {
+    fn cancel_block(&mut self) -> Result<(), DevmodeError> {
         debug!("Canceling block");
         match self.service.cancel_block() {
-            Ok(_) => {}
-            Err(Error::InvalidState(_)) => {}
-            Err(err) => {
-                panic!("Failed to cancel block: {:?}", err);
-            }
-        };
+            Ok(_) => Ok(()),
+            Err(Error::InvalidState(_)) => Ok(()),
+            Err(err) => Err(DevmodeError::from_service("cancel block", err)),
+        }
     }
 
-    fn broadcast_published_block(&mut self, block_id: BlockId) {
-        debug!("Broadcasting published block: {}", to_hex(&block_id));
+    fn broadcast_published_block(&mut self, block_id: BlockId) -> Result<(), DevmodeError> {
+        debug!("Broadcasting published block: {}", hex::encode(&block_id));
         self.service
             .broadcast("published", block_id)
-            .expect("Failed to broadcast published block");
+            .map_err(|err| DevmodeError::from_service("broadcast published block", err))
+    }
+
+    fn broadcast_leaving(&mut self) -> Result<(), DevmodeError> {
+        debug!("Broadcasting leaving message");
+        self.service
+            .broadcast("leaving", vec![])
+            .map_err(|err| DevmodeError::from_service("broadcast leaving", err))
     }
 
-    fn send_block_received(&mut self, block: &Block) {
+    fn send_block_received(&mut self, block: &Block) -> Result<(), DevmodeError> {
         let block = block.clone();
 
         self.service
             .send_to(&block.signer_id, "received", block.block_id)
-            .expect("Failed to send block received");
+            .map_err(|err| DevmodeError::from_service("send block received", err))
     }
 
     #[allow(clippy::ptr_arg)]
-    fn send_block_ack(&mut self, sender_id: &PeerId, block_id: BlockId) {
+    fn send_block_ack(
+        &mut self,
+        sender_id: &PeerId,
+        block_id: BlockId,
+    ) -> Result<(), DevmodeError> {
         self.service
             .send_to(&sender_id, "ack", block_id)
-            .expect("Failed to send block ack");
+            .map_err(|err| DevmodeError::from_service("send block ack", err))
     }
 
-    // Calculate the time to wait between publishing blocks. This will be a
-    // random number between the settings sawtooth.consensus.min_wait_time and
-    // sawtooth.consensus.max_wait_time if max > min, else DEFAULT_WAIT_TIME. If
-    // there is an error parsing those settings, the time will be
-    // DEFAULT_WAIT_TIME.
-    fn calculate_wait_time(&mut self, chain_head_id: BlockId) -> time::Duration {
-        let settings_result = self.service.get_settings(
-            chain_head_id,
-            vec![
-                String::from("sawtooth.consensus.min_wait_time"),
-                String::from("sawtooth.consensus.max_wait_time"),
-            ],
-        );
-
-        let wait_time = if let Ok(settings) = settings_result {
-            let ints: Vec<u64> = vec![
-                &settings["sawtooth.consensus.min_wait_time"],
-                &settings["sawtooth.consensus.max_wait_time"],
-            ]
-            .iter()
-            .map(|string| string.parse::<u64>())
-            .map(|result| result.unwrap_or(0))
-            .collect();
-
-            let min_wait_time: u64 = ints[0];
-            let max_wait_time: u64 = ints[1];
-
-            debug!("Min: {:?} -- Max: {:?}", min_wait_time, max_wait_time);
+    // Get the settings in effect at the given block, fetching them once per
+    // block. Settings are read from the block's state, so every node gets the
+    // same ones for the same block.
+    fn get_settings(&mut self, block: &Block) -> DevmodeSettings {
+        if let Some(settings) = self.settings_cache.get(&block.block_id) {
+            return settings.clone();
+        }
 
-            if min_wait_time >= max_wait_time {
-                DEFAULT_WAIT_TIME
-            } else {
-                rand::thread_rng().gen_range(min_wait_time, max_wait_time)
+        debug!("Getting settings at {}", hex::encode(&block.block_id));
+        match self
+            .service
+            .get_settings(block.block_id.clone(), DevmodeSettings::keys())
+        {
+            Ok(values) => {
+                let settings = DevmodeSettings::from_values(&values);
+                self.settings_cache
+                    .insert(block.block_id.clone(), settings.clone());
+                self.last_settings = Some(settings.clone());
+                settings
             }
-        } else {
-            DEFAULT_WAIT_TIME
-        };
+            Err(err) => {
+                warn!("Failed to get settings, using the last known: {}", err);
+                self.last_settings.clone().unwrap_or_default()
+            }
+        }
+    }
+}
 
-        info!("Wait time: {:?}", wait_time);
+/// Options for the engine that are chosen when it is launched.
+#[derive(Clone)]
+pub struct DevmodeConfig {
+    /// The caller reconnects when the engine stops with an error, so `start`
+    /// returns the error instead of only logging it.
+    pub reconnect: bool,
+    /// Overrides the sawtooth.consensus.devmode.fork_resolution setting.
+    pub fork_resolution: Option<ForkResolution>,
+    /// Overrides the sawtooth.consensus.devmode.publishing_schedule setting.
+    pub publishing_schedule: Option<ScheduleMode>,
+    /// Overrides the sawtooth.consensus.devmode.publishing_seed setting.
+    pub publishing_seed: Option<u64>,
+    /// Keep the block open when the timer expires until the validator has
+    /// batches to put in it, instead of publishing it as soon as it can.
+    pub skip_empty_blocks: bool,
+    /// Only skip empty blocks for this long at each height. After that, the
+    /// engine goes back to asking the validator for content every second
+    /// rather than once per wait time.
+    pub skip_empty_blocks_for: Option<time::Duration>,
+    /// Tell peers the engine is leaving the network when it shuts down.
+    pub announce_leaving: bool,
+    /// Take turns publishing with the other nodes instead of every node
+    /// publishing at every height.
+    pub leader_rotation: bool,
+    /// With `leader_rotation`, how long to wait for the leader before the
+    /// next node in turn publishes instead.
+    pub leader_fallback: time::Duration,
+    /// Overrides the sawtooth.consensus.devmode.publisher setting.
+    pub publisher: Option<PeerKey>,
+    /// Sign consensus payloads with the private key in this file, and only
+    /// accept blocks whose payload is signed by the block's signer.
+    pub signing_key_file: Option<PathBuf>,
+    /// Write consensus data in the versioned format, which also records when
+    /// the block was published. Nodes older than this format fail blocks
+    /// that use it, so only turn it on once every node understands it.
+    pub versioned_payload: bool,
+    /// Reject blocks that don't say when they were published, that say they
+    /// were published before their parent, or more than this far in the
+    /// future.
+    pub max_clock_skew: Option<time::Duration>,
+    /// Reject blocks published sooner than sawtooth.consensus.min_wait_time
+    /// after their parent, going by the publish times in their payloads.
+    pub enforce_min_wait_time: bool,
+    /// Stop checking blocks from a peer once its misbehaviour score goes over
+    /// this threshold, and deal with them as `misbehaviour_action` says.
+    pub misbehaviour_threshold: Option<u64>,
+    pub misbehaviour_action: MisbehaviourAction,
+}
 
-        time::Duration::from_secs(wait_time)
+impl Default for DevmodeConfig {
+    fn default() -> Self {
+        DevmodeConfig {
+            reconnect: false,
+            fork_resolution: None,
+            publishing_schedule: None,
+            publishing_seed: None,
+            skip_empty_blocks: false,
+            skip_empty_blocks_for: None,
+            announce_leaving: false,
+            leader_rotation: false,
+            leader_fallback: DEFAULT_LEADER_FALLBACK,
+            publisher: None,
+            signing_key_file: None,
+            versioned_payload: false,
+            max_clock_skew: None,
+            enforce_min_wait_time: false,
+            misbehaviour_threshold: None,
+            misbehaviour_action: MisbehaviourAction::default(),
+        }
     }
 }
 
-pub struct DevmodeEngine {}
+pub struct DevmodeEngine {
+    config: DevmodeConfig,
+    clock: Arc<dyn Clock>,
+}
 
 impl DevmodeEngine {
-    pub fn new() -> Self {
-        DevmodeEngine {}
+    pub fn new(config: DevmodeConfig) -> Self {
+        DevmodeEngine::with_clock(config, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(config: DevmodeConfig, clock: Arc<dyn Clock>) -> Self {
+        DevmodeEngine { config, clock }
+    }
+
+    fn fork_resolution(&self, settings: &DevmodeSettings) -> ForkResolution {
+        self.config
+            .fork_resolution
+            .or(settings.fork_resolution)
+            .unwrap_or_default()
+    }
+
+    fn publishing_schedule(&self, settings: &DevmodeSettings) -> (ScheduleMode, Option<u64>) {
+        let mode = self
+            .config
+            .publishing_schedule
+            .or(settings.publishing_schedule)
+            .unwrap_or_default();
+        let seed = self.config.publishing_seed.or(settings.publishing_seed);
+        (mode, seed)
+    }
+
+    // Whether this node should publish blocks at all. If a publisher is
+    // designated, only that node does; otherwise every node does.
+    fn is_publisher(&self, settings: &DevmodeSettings, local_id: &PeerId) -> bool {
+        match self
+            .config
+            .publisher
+            .as_ref()
+            .or(settings.publisher.as_ref())
+        {
+            Some(publisher) => publisher.0 == *local_id,
+            None => true,
+        }
+    }
+
+    // With leader rotation, the extra time to wait before publishing the
+    // block at `height`, so that the nodes ahead in turn get to publish first.
+    fn rotation_delay(&self, local_id: &PeerId, peers: &PeerTable, height: u64) -> time::Duration {
+        if !self.config.leader_rotation {
+            return time::Duration::from_secs(0);
+        }
+
+        let rotation = Rotation::new(local_id, peers.ids());
+        let rank = rotation
+            .rank(local_id, height)
+            .expect("Local node is part of the rotation");
+        info!(
+            "Leader for block {} is {}; this node is {} in turn",
+            height,
+            hex::encode(rotation.leader(height)),
+            rank
+        );
+        self.config.leader_fallback * rank as u32
+    }
+
+    // Whether to leave the block open if it has no content, given the time
+    // the engine started building on the current chain head.
+    fn skip_empty_block(&self, height_start: time::Instant, now: time::Instant) -> bool {
+        if !self.config.skip_empty_blocks {
+            return false;
+        }
+        match self.config.skip_empty_blocks_for {
+            Some(limit) => now.duration_since(height_start) < limit,
+            None => true,
+        }
     }
 }
 
-impl Engine for DevmodeEngine {
+impl DevmodeEngine {
     #[allow(clippy::cognitive_complexity)]
-    fn start(
+    fn run(
         &mut self,
         updates: Receiver<Update>,
         service: Box<dyn Service>,
         startup_state: StartupState,
     ) -> Result<(), Error> {
         let mut service = DevmodeService::new(service);
-        let mut chain_head = startup_state.chain_head;
-
-        let mut wait_time = service.calculate_wait_time(chain_head.block_id.clone());
-        let mut published_at_height = false;
-        let mut start = time::Instant::now();
-
-        service.initialize_block();
+        let chain_head = startup_state.chain_head;
+        let local_id = startup_state.local_peer_info.peer_id;
+        let signer = match self.config.signing_key_file {
+            Some(ref path) => {
+                Some(PayloadSigner::from_key_file(path).map_err(Error::InvalidState)?)
+            }
+            None => None,
+        };
+        // Other nodes expect this node's blocks to be signed by its validator
+        // key, and so does this node.
+        if let Some(ref signer) = signer {
+            if signer.public_key() != &local_id[..] {
+                return Err(Error::InvalidState(format!(
+                    "Signing key {} doesn't belong to this validator, {}",
+                    hex::encode(signer.public_key()),
+                    hex::encode(&local_id)
+                )));
+            }
+        }
+        let format = ConsensusFormat {
+            clock: self.clock.clone(),
+            signer,
+            versioned: self.config.versioned_payload,
+            max_clock_skew: self.config.max_clock_skew,
+            enforce_min_wait_time: self.config.enforce_min_wait_time,
+        };
+        let mut tracker = PeerTracker {
+            local_id: local_id.clone(),
+            peers: PeerTable::new(startup_state.peers, self.clock.now()),
+            propagation: PropagationTracker::new(PROPAGATION_TIMEOUT),
+            validation: ValidationTracker::new(),
+            reputation: Reputation::new(
+                self.config.misbehaviour_threshold,
+                self.config.misbehaviour_action,
+            ),
+        };
+        service.cache_block(chain_head.clone());
+        service.chain_head_updated(chain_head.block_id.clone());
+
+        let mut settings = service.get_settings(&chain_head);
+        let (mode, seed) = self.publishing_schedule(&settings);
+        let mut schedule = PublishingSchedule::new(mode, seed);
+        let wait_time = schedule.wait_time(&settings)
+            + self.rotation_delay(&local_id, &tracker.peers, chain_head.block_num + 1);
+        let mut fork_resolution = self.fork_resolution(&settings);
+        let mut fork_resolver = fork_resolution.resolver(&local_id);
+        info!("Using {} fork resolution", fork_resolution);
+        let now = self.clock.now();
+        let mut state = EngineState::new(chain_head, wait_time, now);
+        let mut publishing = self.is_publisher(&settings, &local_id);
+
+        if publishing {
+            service
+                .initialize_block()
+                .and_then(|_| state.initialize(now))
+                .or_else(recover)?;
+        } else {
+            info!("Not the designated publisher, only validating blocks");
+        }
 
         // 1. Wait for an incoming message.
         // 2. Check for exit.
         // 3. Handle the message.
-        // 4. Check for publishing.
+        // 4. Move the block in progress towards publishing.
         loop {
             let incoming_message = updates.recv_timeout(time::Duration::from_millis(10));
 
-            match incoming_message {
+            let result = match incoming_message {
                 Ok(update) => {
                     debug!("Received message: {}", message_type(&update));
 
                     match update {
                         Update::Shutdown => {
+                            let now = self.clock.now();
+                            let announce = self.config.announce_leaving;
+                            if let Err(err) = shutdown(&mut service, &mut state, announce, now) {
+                                warn!("Failed to shut down cleanly: {}", err);
+                            }
                             break;
                         }
                         Update::BlockNew(block) => {
-                            info!("Checking consensus data: {}", DisplayBlock(&block));
-
-                            if block.previous_id == NULL_BLOCK_IDENTIFIER {
-                                warn!("Received genesis block; ignoring");
-                                continue;
-                            }
-
-                            if check_consensus(&block) {
-                                info!("Passed consensus check: {}", DisplayBlock(&block));
-                                service.check_block(block.block_id);
-                            } else {
-                                info!("Failed consensus check: {}", DisplayBlock(&block));
-                                service.fail_block(block.block_id);
-                            }
+                            handle_block_new(&mut service, &format, &mut tracker, block)
                         }
 
                         Update::BlockValid(block_id) => {
-                            let block = service.get_block(&block_id);
-
-                            service.send_block_received(&block);
-
-                            chain_head = service.get_chain_head();
-
-                            info!(
-                                "Choosing between chain heads -- current: {} -- new: {}",
-                                DisplayBlock(&chain_head),
-                                DisplayBlock(&block)
-                            );
-
-                            // Advance the chain if possible.
-                            if block.block_num > chain_head.block_num
-                                || (block.block_num == chain_head.block_num
-                                    && block.block_id > chain_head.block_id)
-                            {
-                                info!("Committing {}", DisplayBlock(&block));
-                                service.commit_block(block_id);
-                            } else if block.block_num < chain_head.block_num {
-                                let mut chain_block = chain_head;
-                                loop {
-                                    chain_block = service.get_block(&chain_block.previous_id);
-                                    if chain_block.block_num == block.block_num {
-                                        break;
-                                    }
-                                }
-                                if block.block_id > chain_block.block_id {
-                                    info!("Switching to new fork {}", DisplayBlock(&block));
-                                    service.commit_block(block_id);
-                                } else {
-                                    info!("Ignoring fork {}", DisplayBlock(&block));
-                                    service.ignore_block(block_id);
-                                }
-                            } else {
-                                info!("Ignoring {}", DisplayBlock(&block));
-                                service.ignore_block(block_id);
-                            }
+                            tracker.validation.valid(&block_id);
+                            handle_block_valid(
+                                &mut service,
+                                &*fork_resolver,
+                                &mut state.chain_head,
+                                block_id,
+                            )
                         }
 
+                        Update::BlockInvalid(block_id) => handle_block_invalid(
+                            &mut service,
+                            &mut state,
+                            &mut tracker,
+                            publishing,
+                            block_id,
+                            self.clock.now(),
+                        ),
+
                         // The chain head was updated, so abandon the
                         // block in progress and start a new one.
                         Update::BlockCommit(new_chain_head) => {
                             info!(
                                 "Chain head updated to {}, abandoning block in progress",
-                                to_hex(&new_chain_head)
+                                hex::encode(&new_chain_head)
                             );
 
-                            service.cancel_block();
-
-                            wait_time = service.calculate_wait_time(new_chain_head.clone());
-                            published_at_height = false;
-                            start = time::Instant::now();
+                            service.chain_head_updated(new_chain_head.clone());
+                            let new_head = service.get_block(&new_chain_head).ok();
+                            let block_num = new_head.as_ref().map(|block| block.block_num);
+                            let new_settings = match new_head {
+                                Some(ref block) => service.get_settings(block),
+                                None => settings.clone(),
+                            };
+                            if new_settings != settings {
+                                let at = block_num
+                                    .map(|block_num| format!("block {}", block_num))
+                                    .unwrap_or_else(|| hex::encode(&new_chain_head));
+                                new_settings.log_changes(&settings, &at);
+                                settings = new_settings;
+                            }
 
-                            service.initialize_block();
-                        }
+                            let (mode, seed) = self.publishing_schedule(&settings);
+                            if mode != schedule.mode() || seed != schedule.seed() {
+                                info!("Using {} publishing schedule, seed {:?}", mode, seed);
+                                schedule = PublishingSchedule::new(mode, seed);
+                            }
+                            let mut wait_time = schedule.wait_time(&settings);
+                            if let Some(block_num) = block_num {
+                                wait_time +=
+                                    self.rotation_delay(&local_id, &tracker.peers, block_num + 1);
+                            }
 
-                        Update::PeerMessage(message, sender_id) => {
-                            match DevmodeMessage::from_str(message.header.message_type.as_ref())
-                                .unwrap()
-                            {
-                                DevmodeMessage::Published => {
-                                    info!(
-                                        "Received block published message from {}: {}",
-                                        to_hex(&sender_id),
-                                        to_hex(&message.content)
-                                    );
-                                }
+                            let new_fork_resolution = self.fork_resolution(&settings);
+                            if new_fork_resolution != fork_resolution {
+                                fork_resolution = new_fork_resolution;
+                                fork_resolver = fork_resolution.resolver(&local_id);
+                                info!("Using {} fork resolution", fork_resolution);
+                            }
 
-                                DevmodeMessage::Received => {
-                                    info!(
-                                        "Received block received message from {}: {}",
-                                        to_hex(&sender_id),
-                                        to_hex(&message.content)
-                                    );
-                                    service.send_block_ack(&sender_id, message.content);
+                            let is_publisher = self.is_publisher(&settings, &local_id);
+                            if is_publisher != publishing {
+                                publishing = is_publisher;
+                                if publishing {
+                                    info!("Now the designated publisher");
+                                } else {
+                                    info!("No longer the designated publisher");
                                 }
+                            }
 
-                                DevmodeMessage::Ack => {
-                                    info!(
-                                        "Received ack message from {}: {}",
-                                        to_hex(&sender_id),
-                                        to_hex(&message.content)
-                                    );
-                                }
+                            let now = self.clock.now();
+                            if publishing {
+                                state.commit(wait_time, now);
+                                restart_block(&mut service, &mut state, now)
+                            } else {
+                                abandon_block(&mut service, &mut state, now)
+                                    .map(|_| state.commit(wait_time, now))
                             }
                         }
 
-                        // Devmode doesn't care about peer notifications
-                        // or invalid blocks.
-                        _ => {}
+                        Update::PeerMessage(message, sender_id) => handle_peer_message(
+                            &mut service,
+                            &mut tracker,
+                            message,
+                            sender_id,
+                            self.clock.now(),
+                        ),
+
+                        Update::PeerConnected(info) => {
+                            info!("Peer {} connected", hex::encode(&info.peer_id));
+                            tracker.peers.connected(info.peer_id, self.clock.now());
+                            Ok(())
+                        }
+
+                        Update::PeerDisconnected(peer_id) => {
+                            if let Some(stats) = tracker.peers.disconnected(&peer_id) {
+                                let now = self.clock.now();
+                                info!(
+                                    "Peer {} disconnected after {:?}: {} blocks received, {} acks, \
+                                     last message {:?} ago",
+                                    hex::encode(&peer_id),
+                                    now.duration_since(stats.connected_at),
+                                    stats.blocks_received,
+                                    stats.acks_received,
+                                    stats.last_message.map(|at| now.duration_since(at))
+                                );
+                            }
+                            for summary in tracker.propagation.peer_disconnected(&peer_id) {
+                                info!("{}", summary);
+                            }
+                            Ok(())
+                        }
                     }
                 }
 
                 Err(RecvTimeoutError::Disconnected) => {
-                    error!("Disconnected from validator");
-                    break;
+                    return Err(Error::ReceiveError("Disconnected from validator".into()));
                 }
 
-                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Timeout) => Ok(()),
+            };
+
+            result.or_else(recover)?;
+
+            let now = self.clock.now();
+            if state.timer_expired(now) {
+                match state.publish {
+                    PublishState::Idle if publishing => {
+                        // Starting a block failed earlier; try again.
+                        restart_block(&mut service, &mut state, now).or_else(recover)?;
+                    }
+                    PublishState::Initialized => {
+                        info!("Timer expired -- publishing block");
+                        state.start_publishing(now).or_else(recover)?;
+                    }
+                    _ => (),
+                }
             }
 
-            if !published_at_height && time::Instant::now().duration_since(start) > wait_time {
-                info!("Timer expired -- publishing block");
-                let new_block_id = service.finalize_block();
-                published_at_height = true;
+            if state.should_retry(now) {
+                let skip_empty = self.skip_empty_block(state.height_start, now);
+                match advance_publishing(&mut service, &mut state, &format, skip_empty, now) {
+                    Ok(Some(block_id)) => {
+                        tracker
+                            .propagation
+                            .published(block_id, tracker.peers.ids(), now)
+                    }
+                    Ok(None) => (),
+                    Err(err) => {
+                        if err.is_fatal() {
+                            return Err(err.into());
+                        }
+
+                        // Start over with a fresh block and try again once
+                        // the timer expires.
+                        warn!("Failed to publish block: {}", err);
+                        restart_block(&mut service, &mut state, now).or_else(recover)?;
+                    }
+                }
+            }
 
-                service.broadcast_published_block(new_block_id);
+            for summary in tracker.propagation.expire(now) {
+                info!("{}", summary);
             }
         }
 
         Ok(())
     }
+}
+
+impl Engine for DevmodeEngine {
+    fn start(
+        &mut self,
+        updates: Receiver<Update>,
+        service: Box<dyn Service>,
+        startup_state: StartupState,
+    ) -> Result<(), Error> {
+        match self.run(updates, service, startup_state) {
+            Err(err) if !self.config.reconnect => {
+                error!("{}", err);
+                Ok(())
+            }
+            result => result,
+        }
+    }
 
     fn version(&self) -> String {
         "0.1".into()
@@ -397,64 +922,1866 @@ impl Engine for DevmodeEngine {
     }
 }
 
-struct DisplayBlock<'b>(&'b Block);
-
-impl<'b> fmt::Display for DisplayBlock<'b> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.write_str("Block(")?;
-        f.write_str(&self.0.block_num.to_string())?;
-        write!(f, ", id: {}", to_hex(&self.0.block_id))?;
-        write!(f, ", prev: {})", to_hex(&self.0.previous_id))
+// Decide whether the engine can keep going after an error. Fatal errors are
+// passed on so the caller can stop; anything else is logged and dropped.
+fn recover(err: DevmodeError) -> Result<(), DevmodeError> {
+    if err.is_fatal() {
+        Err(err)
+    } else {
+        warn!("{}", err);
+        Ok(())
     }
 }
 
-fn to_hex(bytes: &[u8]) -> String {
-    let mut buf = String::new();
-    for b in bytes {
-        write!(&mut buf, "{:0x}", b).expect("Unable to write to string");
-    }
+// Abandon the block in progress, if any, and start a new one on the current
+// chain head.
+fn restart_block(
+    service: &mut DevmodeService,
+    state: &mut EngineState,
+    now: time::Instant,
+) -> Result<(), DevmodeError> {
+    state.cancel(now);
+    service.cancel_block()?;
+    service.initialize_block()?;
+    state.initialize(now)
+}
 
-    buf
+// Abandon the block in progress, if there is one, without starting another.
+fn abandon_block(
+    service: &mut DevmodeService,
+    state: &mut EngineState,
+    now: time::Instant,
+) -> Result<(), DevmodeError> {
+    let in_progress = !matches!(state.publish, PublishState::Idle | PublishState::Published);
+    state.cancel(now);
+    if in_progress {
+        service.cancel_block()?;
+    }
+    Ok(())
 }
 
-fn message_type(update: &Update) -> &str {
-    match *update {
-        Update::PeerConnected(_) => "PeerConnected",
-        Update::PeerDisconnected(_) => "PeerDisconnected",
-        Update::PeerMessage(..) => "PeerMessage",
-        Update::BlockNew(_) => "BlockNew",
-        Update::BlockValid(_) => "BlockValid",
-        Update::BlockInvalid(_) => "BlockInvalid",
-        Update::BlockCommit(_) => "BlockCommit",
-        Update::Shutdown => "Shutdown",
+// Leave the network cleanly: abandon the block in progress so the validator
+// doesn't keep it around, and let peers know if asked to.
+fn shutdown(
+    service: &mut DevmodeService,
+    state: &mut EngineState,
+    announce_leaving: bool,
+    now: time::Instant,
+) -> Result<(), DevmodeError> {
+    info!("Shutting down");
+    abandon_block(service, state, now)?;
+    if announce_leaving {
+        service.broadcast_leaving()?;
     }
+    Ok(())
 }
 
-fn check_consensus(block: &Block) -> bool {
-    block.payload == create_consensus(&block.summary)
+// Move the block in progress towards being published for as long as the
+// validator is ready, without waiting on it. Returns the id of the block if
+// it was published.
+fn advance_publishing(
+    service: &mut DevmodeService,
+    state: &mut EngineState,
+    format: &ConsensusFormat,
+    skip_empty: bool,
+    now: time::Instant,
+) -> Result<Option<BlockId>, DevmodeError> {
+    loop {
+        match state.publish.clone() {
+            PublishState::WaitingToSummarize => match service.summarize_block()? {
+                Some(summary) => {
+                    let consensus = format.create(&summary, state.wait_time)?;
+                    state.summarized(consensus)?
+                }
+                None if skip_empty => {
+                    // Nothing to publish yet; check again once the timer
+                    // expires again.
+                    debug!("Block is empty, waiting for batches");
+                    return state.skip_empty(now).map(|_| None);
+                }
+                None => return state.not_ready(now).map(|_| None),
+            },
+            PublishState::WaitingToFinalize(consensus) => {
+                match service.finalize_block(consensus)? {
+                    Some(block_id) => {
+                        state.published()?;
+                        service.broadcast_published_block(block_id.clone())?;
+                        return Ok(Some(block_id));
+                    }
+                    None => return state.not_ready(now).map(|_| None),
+                }
+            }
+            _ => return Ok(None),
+        }
+    }
 }
 
-fn create_consensus(summary: &[u8]) -> Vec<u8> {
-    let mut consensus: Vec<u8> = Vec::from(&b"Devmode"[..]);
-    consensus.extend_from_slice(summary);
-    consensus
+/// What the engine keeps track of about the other nodes: who is connected,
+/// how this node's blocks reach them, and how their blocks fare.
+struct PeerTracker {
+    local_id: PeerId,
+    peers: PeerTable,
+    propagation: PropagationTracker,
+    validation: ValidationTracker,
+    reputation: Reputation,
 }
 
-pub enum DevmodeMessage {
-    Ack,
-    Published,
-    Received,
+impl PeerTracker {
+    // This node's own mistakes don't count against it.
+    fn penalize(&mut self, peer_id: &PeerId, misbehaviour: Misbehaviour) {
+        if *peer_id != self.local_id {
+            self.reputation.penalize(peer_id, misbehaviour);
+        }
+    }
 }
 
-impl FromStr for DevmodeMessage {
-    type Err = &'static str;
+fn handle_block_new(
+    service: &mut DevmodeService,
+    format: &ConsensusFormat,
+    tracker: &mut PeerTracker,
+    block: Block,
+) -> Result<(), DevmodeError> {
+    info!("Checking consensus data: {}", DisplayBlock(&block));
+
+    if block.previous_id == NULL_BLOCK_IDENTIFIER {
+        warn!("Received genesis block; ignoring");
+        return Ok(());
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "ack" => Ok(DevmodeMessage::Ack),
-            "published" => Ok(DevmodeMessage::Published),
-            "received" => Ok(DevmodeMessage::Received),
-            _ => Err("Invalid message type"),
+    let reputation = &tracker.reputation;
+    if let Some(action) = reputation.sanction(&block.signer_id) {
+        warn!(
+            "Not checking {}: signer's misbehaviour score {} is over the threshold {}; will {} it",
+            DisplayBlock(&block),
+            reputation.score(&block.signer_id),
+            reputation.threshold().unwrap_or_default(),
+            action
+        );
+        return match action {
+            MisbehaviourAction::Ignore => service.ignore_block(block.block_id),
+            MisbehaviourAction::Fail => service.fail_block(block.block_id),
+        };
+    }
+
+    if !format.check(&block) {
+        info!("Failed consensus check: {}", DisplayBlock(&block));
+        tracker.penalize(&block.signer_id, Misbehaviour::FailedConsensusCheck);
+        return service.fail_block(block.block_id);
+    }
+
+    // A block published at the wrong time may only mean the clocks disagree,
+    // so it's failed without counting against its signer.
+    if format.checks_time() {
+        let parent = match service.get_block(&block.previous_id) {
+            Ok(parent) => parent,
+            Err(err) => return ignore_undecided(service, block.block_id, err),
+        };
+        let settings = service.get_settings(&parent);
+        if !format.check_time(&block, &BlockContext { parent, settings }) {
+            info!("Failed publish time check: {}", DisplayBlock(&block));
+            return service.fail_block(block.block_id);
+        }
+    }
+
+    info!("Passed consensus check: {}", DisplayBlock(&block));
+    service.cache_block(block.clone());
+    tracker
+        .validation
+        .checking(block.block_id.clone(), block.signer_id);
+    service.check_block(block.block_id)
+}
+
+// The validator found a block invalid after it passed the consensus check. If
+// it was the block this node just published, there won't be a commit to move
+// on from, so start a new block right away.
+fn handle_block_invalid(
+    service: &mut DevmodeService,
+    state: &mut EngineState,
+    tracker: &mut PeerTracker,
+    publishing: bool,
+    block_id: BlockId,
+    now: time::Instant,
+) -> Result<(), DevmodeError> {
+    service.forget_block(&block_id);
+
+    let (signer_id, count) = match tracker.validation.invalid(&block_id) {
+        Some(invalid) => invalid,
+        None => {
+            warn!("Block {} failed validation", hex::encode(&block_id));
+            return Ok(());
         }
+    };
+    warn!(
+        "Block {} from {} failed validation; {} invalid blocks from this signer",
+        hex::encode(&block_id),
+        hex::encode(&signer_id),
+        count
+    );
+
+    tracker.penalize(&signer_id, Misbehaviour::InvalidBlock);
+    if signer_id == tracker.local_id
+        && publishing
+        && matches!(state.publish, PublishState::Published)
+    {
+        info!("Published block was invalid, starting a new one");
+        restart_block(service, state, now)
+    } else {
+        Ok(())
+    }
+}
+
+fn handle_block_valid(
+    service: &mut DevmodeService,
+    fork_resolver: &dyn ForkResolver,
+    chain_head: &mut Block,
+    block_id: BlockId,
+) -> Result<(), DevmodeError> {
+    let block = match service.get_block(&block_id) {
+        Ok(block) => block,
+        Err(err) => return ignore_undecided(service, block_id, err),
+    };
+
+    // The signer may have left the network; that shouldn't stop us from
+    // choosing between forks.
+    service.send_block_received(&block).or_else(recover)?;
+
+    *chain_head = match service.get_chain_head() {
+        Ok(chain_head) => chain_head,
+        Err(err) => return ignore_undecided(service, block_id, err),
+    };
+
+    info!(
+        "Choosing between chain heads -- current: {} -- new: {}",
+        DisplayBlock(chain_head),
+        DisplayBlock(&block)
+    );
+
+    let comparison = match compare_forks(service, chain_head, &block) {
+        Ok(comparison) => comparison,
+        Err(err) => return ignore_undecided(service, block_id, err),
+    };
+    if comparison.chain_depth > 0 && comparison.fork_depth > 0 {
+        info!(
+            "Fork at {} -- current chain depth: {} -- new fork depth: {}",
+            DisplayBlock(&comparison.ancestor),
+            comparison.chain_depth,
+            comparison.fork_depth
+        );
+    }
+
+    let fork = Fork {
+        chain_head,
+        block: &block,
+        comparison: &comparison,
+    };
+    let behind = block.block_num < chain_head.block_num;
+
+    // Advance the chain if possible.
+    match (fork_resolver.should_switch(&fork), behind) {
+        (true, false) => {
+            info!("Committing {}", DisplayBlock(&block));
+            service.commit_block(block_id)
+        }
+        (true, true) => {
+            info!("Switching to new fork {}", DisplayBlock(&block));
+            service.commit_block(block_id)
+        }
+        (false, true) => {
+            info!("Ignoring fork {}", DisplayBlock(&block));
+            service.ignore_block(block_id)
+        }
+        (false, false) => {
+            info!("Ignoring {}", DisplayBlock(&block));
+            service.ignore_block(block_id)
+        }
+    }
+}
+
+// A block that can't be checked against its parent, or compared with the
+// chain head once valid, is ignored, so that the validator isn't left waiting
+// for a decision on it. Fatal errors are passed on as they are.
+fn ignore_undecided(
+    service: &mut DevmodeService,
+    block_id: BlockId,
+    err: DevmodeError,
+) -> Result<(), DevmodeError> {
+    if err.is_fatal() {
+        return Err(err);
+    }
+    warn!("Ignoring block {}: {}", hex::encode(&block_id), err);
+    service.ignore_block(block_id)
+}
+
+// Walk the current chain and the new block's branch back to their common
+// ancestor. Each round-trip to the validator fetches the next block of both
+// branches at once, and blocks that are already cached aren't fetched again.
+fn compare_forks(
+    service: &mut DevmodeService,
+    chain_head: &Block,
+    block: &Block,
+) -> Result<ForkComparison, DevmodeError> {
+    let mut chain_block = chain_head.clone();
+    let mut fork_block = block.clone();
+    let mut chain_block_at_height = None;
+
+    loop {
+        if chain_block.block_num == block.block_num {
+            chain_block_at_height = Some(chain_block.clone());
+        }
+
+        if chain_block.block_id == fork_block.block_id {
+            break;
+        }
+
+        // Step back whichever branch is higher, or both if they are level.
+        let step_chain = chain_block.block_num >= fork_block.block_num;
+        let step_fork = fork_block.block_num >= chain_block.block_num;
+
+        let mut previous_ids = vec![];
+        if step_chain {
+            previous_ids.push(chain_block.previous_id.clone());
+        }
+        if step_fork {
+            previous_ids.push(fork_block.previous_id.clone());
+        }
+
+        if previous_ids
+            .iter()
+            .any(|id| id.as_slice() == NULL_BLOCK_IDENTIFIER)
+        {
+            return Err(DevmodeError::UnknownBlock(format!(
+                "No common ancestor for {} and {}",
+                DisplayBlock(chain_head),
+                DisplayBlock(block)
+            )));
+        }
+
+        let previous_blocks = service.get_blocks(previous_ids)?;
+        if step_chain {
+            chain_block = found_block(&previous_blocks, &chain_block.previous_id)?;
+        }
+        if step_fork {
+            fork_block = found_block(&previous_blocks, &fork_block.previous_id)?;
+        }
+    }
+
+    Ok(ForkComparison {
+        chain_depth: chain_head.block_num - chain_block.block_num,
+        fork_depth: block.block_num - chain_block.block_num,
+        ancestor: chain_block,
+        chain_block: chain_block_at_height,
+    })
+}
+
+#[allow(clippy::ptr_arg)]
+fn found_block(
+    blocks: &HashMap<BlockId, Block>,
+    block_id: &BlockId,
+) -> Result<Block, DevmodeError> {
+    blocks.get(block_id).cloned().ok_or_else(|| {
+        DevmodeError::UnknownBlock(format!(
+            "Failed to get block: {} was not returned",
+            hex::encode(block_id)
+        ))
+    })
+}
+
+fn handle_peer_message(
+    service: &mut DevmodeService,
+    tracker: &mut PeerTracker,
+    message: PeerMessage,
+    sender_id: PeerId,
+    now: time::Instant,
+) -> Result<(), DevmodeError> {
+    if !tracker.peers.message_received(&sender_id, now) {
+        debug!("Message from unknown peer {}", hex::encode(&sender_id));
+    }
+
+    let message_type = match DevmodeMessage::from_str(message.header.message_type.as_ref()) {
+        Ok(message_type) => message_type,
+        Err(err) => {
+            warn!(
+                "Ignoring message from {}: {}: {}",
+                hex::encode(&sender_id),
+                err,
+                message.header.message_type
+            );
+            tracker.penalize(&sender_id, Misbehaviour::UnknownMessage);
+            return Ok(());
+        }
+    };
+
+    match message_type {
+        DevmodeMessage::Published => {
+            info!(
+                "Received block published message from {}: {}",
+                hex::encode(&sender_id),
+                hex::encode(&message.content)
+            );
+            Ok(())
+        }
+
+        DevmodeMessage::Received => {
+            info!(
+                "Received block received message from {}: {}",
+                hex::encode(&sender_id),
+                hex::encode(&message.content)
+            );
+            tracker.peers.block_received(&sender_id);
+            if let Some(summary) = tracker
+                .propagation
+                .received(&message.content, &sender_id, now)
+            {
+                info!("{}", summary);
+            }
+            service.send_block_ack(&sender_id, message.content)
+        }
+
+        DevmodeMessage::Ack => {
+            info!(
+                "Received ack message from {}: {}",
+                hex::encode(&sender_id),
+                hex::encode(&message.content)
+            );
+            tracker.peers.ack_received(&sender_id);
+            Ok(())
+        }
+
+        DevmodeMessage::Leaving => {
+            info!("Peer {} is leaving the network", hex::encode(&sender_id));
+            Ok(())
+        }
+    }
+}
+
+struct DisplayBlock<'b>(&'b Block);
+
+impl<'b> fmt::Display for DisplayBlock<'b> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Block(")?;
+        f.write_str(&self.0.block_num.to_string())?;
+        write!(f, ", id: {}", hex::encode(&self.0.block_id))?;
+        write!(f, ", prev: {})", hex::encode(&self.0.previous_id))
+    }
+}
+
+fn message_type(update: &Update) -> &str {
+    match *update {
+        Update::PeerConnected(_) => "PeerConnected",
+        Update::PeerDisconnected(_) => "PeerDisconnected",
+        Update::PeerMessage(..) => "PeerMessage",
+        Update::BlockNew(_) => "BlockNew",
+        Update::BlockValid(_) => "BlockValid",
+        Update::BlockInvalid(_) => "BlockInvalid",
+        Update::BlockCommit(_) => "BlockCommit",
+        Update::Shutdown => "Shutdown",
+    }
+}
+
+/// What a new block's publish time is checked against.
+struct BlockContext {
+    parent: Block,
+    /// The settings in effect on top of `parent`.
+    settings: DevmodeSettings,
+}
+
+/// How this node writes consensus data into the blocks it publishes, and
+/// checks it in other nodes' blocks. See the `payload` module for the formats.
+struct ConsensusFormat {
+    clock: Arc<dyn Clock>,
+    signer: Option<PayloadSigner>,
+    versioned: bool,
+    max_clock_skew: Option<time::Duration>,
+    enforce_min_wait_time: bool,
+}
+
+impl ConsensusFormat {
+    fn create(&self, summary: &[u8], wait_time: time::Duration) -> Result<Vec<u8>, DevmodeError> {
+        if !self.versioned {
+            let mut consensus = LEGACY_MAGIC.to_vec();
+            consensus.extend_from_slice(summary);
+            if let Some(ref signer) = self.signer {
+                consensus.extend(sign(signer, summary)?);
+            }
+            return Ok(consensus);
+        }
+
+        let mut payload = PayloadV1 {
+            published_at: Some(self.now_millis()),
+            wait_time: Some(duration_to_millis(wait_time)),
+            software_version: Some(env!("CARGO_PKG_VERSION").into()),
+            ..PayloadV1::new(summary.to_vec())
+        };
+        if let Some(ref signer) = self.signer {
+            payload.signature = Some(sign(signer, &payload.encode())?);
+        }
+        Ok(payload.encode())
+    }
+
+    fn checks_time(&self) -> bool {
+        self.max_clock_skew.is_some() || self.enforce_min_wait_time
+    }
+
+    fn check(&self, block: &Block) -> bool {
+        let payload = match ConsensusPayload::parse(&block.payload) {
+            Ok(payload) => payload,
+            Err(err) => {
+                debug!("Invalid consensus data: {}", err);
+                return false;
+            }
+        };
+
+        match payload {
+            ConsensusPayload::Legacy(ref rest) => self.check_legacy(block, rest),
+            ConsensusPayload::V1(ref payload) => self.check_v1(block, payload),
+        }
+    }
+
+    // The legacy format is the summary followed, in signed mode, by the
+    // signature over the summary.
+    fn check_legacy(&self, block: &Block, rest: &[u8]) -> bool {
+        if !rest.starts_with(&block.summary) {
+            return false;
+        }
+
+        let signature = &rest[block.summary.len()..];
+        match self.signer {
+            Some(ref signer) => signer.verify(signature, &block.summary, &block.signer_id),
+            None => signature.is_empty(),
+        }
+    }
+
+    fn check_v1(&self, block: &Block, payload: &PayloadV1) -> bool {
+        if payload.summary != block.summary {
+            return false;
+        }
+
+        match (self.signer.as_ref(), payload.signature.as_ref()) {
+            (Some(signer), Some(signature)) => signer.verify(
+                signature,
+                payload.signed_bytes(&block.payload),
+                &block.signer_id,
+            ),
+            (Some(_), None) | (None, Some(_)) => false,
+            (None, None) => true,
+        }
+    }
+
+    // Only needed when `checks_time`.
+    fn check_time(&self, block: &Block, context: &BlockContext) -> bool {
+        let published_at = match published_at_of(block) {
+            Some(published_at) => published_at,
+            None => {
+                debug!("Block doesn't say when it was published");
+                return false;
+            }
+        };
+
+        if let Some(max_clock_skew) = self.max_clock_skew {
+            let now = self.now_millis();
+            if published_at > now.saturating_add(duration_to_millis(max_clock_skew)) {
+                debug!(
+                    "Block was published {} ms in the future",
+                    published_at - now
+                );
+                return false;
+            }
+        }
+
+        // The parent may predate publish times, e.g. the genesis block.
+        let parent_published_at = match published_at_of(&context.parent) {
+            Some(parent_published_at) => parent_published_at,
+            None => return true,
+        };
+        if published_at < parent_published_at {
+            debug!(
+                "Block was published {} ms before its parent",
+                parent_published_at - published_at
+            );
+            return false;
+        }
+        let (min_wait_time, _) = wait_time_range(&context.settings);
+        if self.enforce_min_wait_time && published_at - parent_published_at < min_wait_time {
+            debug!(
+                "Block was published {} ms after its parent, sooner than the minimum wait time of {} ms",
+                published_at - parent_published_at,
+                min_wait_time
+            );
+            return false;
+        }
+
+        true
+    }
+
+    fn now_millis(&self) -> u64 {
+        self.clock
+            .system_time()
+            .duration_since(UNIX_EPOCH)
+            .map(duration_to_millis)
+            .unwrap_or(0)
+    }
+}
+
+fn published_at_of(block: &Block) -> Option<u64> {
+    ConsensusPayload::parse(&block.payload)
+        .ok()
+        .and_then(|payload| payload.published_at())
+}
+
+fn sign(signer: &PayloadSigner, message: &[u8]) -> Result<Vec<u8>, DevmodeError> {
+    signer.sign(message).map_err(DevmodeError::RequestFailed)
+}
+
+pub enum DevmodeMessage {
+    Ack,
+    Published,
+    Received,
+    Leaving,
+}
+
+impl FromStr for DevmodeMessage {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ack" => Ok(DevmodeMessage::Ack),
+            "published" => Ok(DevmodeMessage::Published),
+            "received" => Ok(DevmodeMessage::Received),
+            "leaving" => Ok(DevmodeMessage::Leaving),
+            _ => Err("Invalid message type"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::*;
+
+    fn engine(clock: &Arc<ManualClock>) -> DevmodeEngine {
+        DevmodeEngine::with_clock(DevmodeConfig::default(), clock.clone())
+    }
+
+    fn service_with_head(chain_head: &Block) -> MockService {
+        let service = MockService::new();
+        service.set_chain_head(chain_head.clone());
+        service.set_setting("sawtooth.consensus.min_wait_time", "0");
+        service.set_setting("sawtooth.consensus.max_wait_time", "0");
+        service
+    }
+
+    // An update that leaves the engine's state alone: a peer that never
+    // connected disconnecting.
+    fn no_op() -> Update {
+        Update::PeerDisconnected(b"stranger".to_vec())
+    }
+
+    // Updates that let the engine's main loop go around `count` times.
+    fn idle(count: usize) -> Vec<Update> {
+        (0..count).map(|_| no_op()).collect()
+    }
+
+    // The actions taken on the service, with the consensus data of finalized
+    // blocks left out since, in the versioned format, it depends on the time.
+    fn actions_without_consensus(service: &MockService) -> Vec<ServiceCall> {
+        service
+            .actions()
+            .into_iter()
+            .map(|call| match call {
+                ServiceCall::FinalizeBlock(_) => ServiceCall::FinalizeBlock(vec![]),
+                call => call,
+            })
+            .collect()
+    }
+
+    fn decisions(service: &MockService) -> Vec<ServiceCall> {
+        service
+            .actions()
+            .into_iter()
+            .filter(|call| {
+                matches!(
+                    *call,
+                    ServiceCall::CheckBlocks(_)
+                        | ServiceCall::FailBlock(_)
+                        | ServiceCall::CommitBlock(_)
+                        | ServiceCall::IgnoreBlock(_)
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn new_block_with_valid_consensus_is_checked() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let service = service_with_head(&genesis);
+
+        let mut invalid = block("b2", "genesis", 1);
+        invalid.payload = b"Bogus".to_vec();
+
+        run_updates(
+            &mut engine(&Arc::new(ManualClock::new())),
+            &service,
+            startup_state(genesis),
+            vec![
+                Update::BlockNew(block("b1", "genesis", 1)),
+                Update::BlockNew(invalid),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            decisions(&service),
+            vec![
+                ServiceCall::CheckBlocks(vec![b"b1".to_vec()]),
+                ServiceCall::FailBlock(b"b2".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn valid_block_extending_chain_is_committed() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let b1 = block("b1", "genesis", 1);
+        let service = service_with_head(&genesis);
+        service.add_block(b1);
+
+        run_updates(
+            &mut engine(&Arc::new(ManualClock::new())),
+            &service,
+            startup_state(genesis),
+            vec![Update::BlockValid(b"b1".to_vec())],
+        )
+        .unwrap();
+
+        assert_eq!(
+            decisions(&service),
+            vec![ServiceCall::CommitBlock(b"b1".to_vec())]
+        );
+        assert!(service.actions().contains(&ServiceCall::SendTo(
+            b"signer".to_vec(),
+            "received".into(),
+            b"b1".to_vec(),
+        )));
+    }
+
+    #[test]
+    fn shorter_fork_with_lower_id_is_ignored() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let b1 = block("b1", "genesis", 1);
+        let b2 = block("b2", "b1", 2);
+        let a1 = block("a1", "genesis", 1);
+        let service = service_with_head(&b2);
+        service.add_block(genesis);
+        service.add_block(b1);
+        service.add_block(a1);
+
+        run_updates(
+            &mut engine(&Arc::new(ManualClock::new())),
+            &service,
+            startup_state(b2),
+            vec![Update::BlockValid(b"a1".to_vec())],
+        )
+        .unwrap();
+
+        assert_eq!(
+            decisions(&service),
+            vec![ServiceCall::IgnoreBlock(b"a1".to_vec())]
+        );
+    }
+
+    #[test]
+    fn block_is_published_only_once_timer_expires() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let service = service_with_head(&genesis);
+
+        run_updates(
+            &mut engine(&Arc::new(ManualClock::new())),
+            &service,
+            startup_state(genesis.clone()),
+            idle(1),
+        )
+        .unwrap();
+        // The unpublished block is cancelled on shutdown.
+        assert_eq!(
+            service.actions(),
+            vec![ServiceCall::InitializeBlock(None), ServiceCall::CancelBlock]
+        );
+
+        let service = service_with_head(&genesis);
+        run_updates(
+            &mut engine(&Arc::new(ManualClock::with_tick(
+                time::Duration::from_secs(1),
+            ))),
+            &service,
+            startup_state(genesis),
+            idle(1),
+        )
+        .unwrap();
+        assert_eq!(
+            actions_without_consensus(&service),
+            vec![
+                ServiceCall::InitializeBlock(None),
+                ServiceCall::SummarizeBlock,
+                ServiceCall::FinalizeBlock(vec![]),
+                ServiceCall::Broadcast("published".into(), b"published-1".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn block_not_ready_is_retried_without_blocking() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let service = service_with_head(&genesis);
+        service.push_summarize_result(Err(Error::BlockNotReady));
+        service.push_summarize_result(Err(Error::BlockNotReady));
+        service.push_finalize_result(Err(Error::BlockNotReady));
+
+        run_updates(
+            &mut engine(&Arc::new(ManualClock::with_tick(
+                time::Duration::from_secs(1),
+            ))),
+            &service,
+            startup_state(genesis),
+            idle(6),
+        )
+        .unwrap();
+
+        assert_eq!(
+            actions_without_consensus(&service),
+            vec![
+                ServiceCall::InitializeBlock(None),
+                ServiceCall::SummarizeBlock,
+                ServiceCall::SummarizeBlock,
+                ServiceCall::SummarizeBlock,
+                ServiceCall::FinalizeBlock(vec![]),
+                ServiceCall::FinalizeBlock(vec![]),
+                ServiceCall::Broadcast("published".into(), b"published-1".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn pending_publish_is_cancelled_on_commit() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let service = service_with_head(&genesis);
+        service.add_block(block("b1", "genesis", 1));
+        service.push_summarize_result(Err(Error::BlockNotReady));
+        service.push_summarize_result(Err(Error::BlockNotReady));
+
+        run_updates(
+            &mut engine(&Arc::new(ManualClock::with_tick(
+                time::Duration::from_secs(1),
+            ))),
+            &service,
+            startup_state(genesis),
+            vec![no_op(), Update::BlockCommit(b"b1".to_vec())],
+        )
+        .unwrap();
+
+        assert_eq!(
+            service.actions(),
+            vec![
+                ServiceCall::InitializeBlock(None),
+                ServiceCall::SummarizeBlock,
+                ServiceCall::CancelBlock,
+                ServiceCall::InitializeBlock(None),
+                ServiceCall::SummarizeBlock,
+                ServiceCall::CancelBlock,
+            ]
+        );
+    }
+
+    const SECOND: time::Duration = time::Duration::from_secs(1);
+
+    // Run the engine for a while with a validator that has no batches, and
+    // count how many times it asks to summarize the block. Every look at the
+    // clock moves it on by `tick`.
+    fn summarize_calls_while_empty(
+        config: DevmodeConfig,
+        wait_time: &str,
+        tick: time::Duration,
+        updates: usize,
+    ) -> usize {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let service = service_with_head(&genesis);
+        service.set_setting("sawtooth.consensus.min_wait_time", wait_time);
+        service.set_setting("sawtooth.consensus.max_wait_time", wait_time);
+        for _ in 0..updates * 2 {
+            service.push_summarize_result(Err(Error::BlockNotReady));
+        }
+        let clock = Arc::new(ManualClock::with_tick(tick));
+
+        run_updates(
+            &mut DevmodeEngine::with_clock(config, clock),
+            &service,
+            startup_state(genesis),
+            idle(updates),
+        )
+        .unwrap();
+
+        service
+            .actions()
+            .into_iter()
+            .filter(|call| *call == ServiceCall::SummarizeBlock)
+            .count()
+    }
+
+    #[test]
+    fn empty_block_with_no_wait_time_is_asked_for_once_a_second() {
+        let tick = time::Duration::from_millis(10);
+        let skipping = summarize_calls_while_empty(
+            DevmodeConfig {
+                skip_empty_blocks: true,
+                ..DevmodeConfig::default()
+            },
+            "0",
+            tick,
+            200,
+        );
+        let polling = summarize_calls_while_empty(DevmodeConfig::default(), "0", tick, 200);
+
+        assert!(skipping <= polling, "{} <= {}", skipping, polling);
+    }
+
+    #[test]
+    fn empty_block_waits_for_the_timer_before_asking_again() {
+        let skipping = summarize_calls_while_empty(
+            DevmodeConfig {
+                skip_empty_blocks: true,
+                ..DevmodeConfig::default()
+            },
+            "3",
+            SECOND,
+            24,
+        );
+        let polling = summarize_calls_while_empty(DevmodeConfig::default(), "3", SECOND, 24);
+
+        assert!(skipping < polling);
+    }
+
+    #[test]
+    fn empty_blocks_are_only_skipped_for_the_limit() {
+        let skipping = summarize_calls_while_empty(
+            DevmodeConfig {
+                skip_empty_blocks: true,
+                ..DevmodeConfig::default()
+            },
+            "3",
+            SECOND,
+            24,
+        );
+        let limited = summarize_calls_while_empty(
+            DevmodeConfig {
+                skip_empty_blocks: true,
+                skip_empty_blocks_for: Some(time::Duration::from_secs(10)),
+                ..DevmodeConfig::default()
+            },
+            "3",
+            SECOND,
+            24,
+        );
+        let polling = summarize_calls_while_empty(DevmodeConfig::default(), "3", SECOND, 24);
+
+        assert!(skipping < limited, "{} < {}", skipping, limited);
+        assert!(limited < polling, "{} < {}", limited, polling);
+    }
+
+    #[test]
+    fn state_moves_through_publishing_in_order() {
+        let now = time::Instant::now();
+        let mut state = EngineState::new(
+            block("genesis", "\0\0\0\0\0\0\0\0", 0),
+            time::Duration::from_secs(1),
+            now,
+        );
+
+        state.initialize(now).unwrap();
+        assert!(!state.timer_expired(now));
+        assert!(state.timer_expired(now + time::Duration::from_secs(2)));
+        state.start_publishing(now).unwrap();
+        state.summarized(b"Devmode".to_vec()).unwrap();
+        assert_eq!(
+            state.publish,
+            PublishState::WaitingToFinalize(b"Devmode".to_vec())
+        );
+        state.published().unwrap();
+        assert_eq!(state.publish, PublishState::Published);
+
+        state.commit(time::Duration::from_secs(5), now);
+        assert_eq!(state.publish, PublishState::Idle);
+        assert_eq!(state.wait_time, time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn state_refuses_to_finalize_without_an_initialized_block() {
+        let now = time::Instant::now();
+        let mut state = EngineState::new(
+            block("genesis", "\0\0\0\0\0\0\0\0", 0),
+            time::Duration::from_secs(0),
+            now,
+        );
+
+        assert!(state.start_publishing(now).is_err());
+        assert!(state.summarized(vec![]).is_err());
+        assert!(state.published().is_err());
+        assert!(state.not_ready(now).is_err());
+        assert_eq!(state.publish, PublishState::Idle);
+
+        state.initialize(now).unwrap();
+        assert!(state.initialize(now).is_err());
+        assert!(state.published().is_err());
+        assert_eq!(state.publish, PublishState::Initialized);
+    }
+
+    #[test]
+    fn shutdown_cancels_block_and_announces_leaving() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let service = service_with_head(&genesis);
+        let config = DevmodeConfig {
+            announce_leaving: true,
+            ..DevmodeConfig::default()
+        };
+
+        run_updates(
+            &mut DevmodeEngine::with_clock(config, Arc::new(ManualClock::new())),
+            &service,
+            startup_state(genesis),
+            vec![],
+        )
+        .unwrap();
+
+        assert_eq!(
+            service.actions(),
+            vec![
+                ServiceCall::InitializeBlock(None),
+                ServiceCall::CancelBlock,
+                ServiceCall::Broadcast("leaving".into(), vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rotation_waits_for_the_leader() {
+        let published = |updates| {
+            let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+            let service = service_with_head(&genesis);
+            let mut startup = startup_state(genesis);
+            startup.peers = vec![PeerInfo {
+                peer_id: b"peer".to_vec(),
+            }];
+            let config = DevmodeConfig {
+                leader_rotation: true,
+                leader_fallback: time::Duration::from_secs(5),
+                ..DevmodeConfig::default()
+            };
+            let clock = Arc::new(ManualClock::with_tick(time::Duration::from_secs(1)));
+
+            run_updates(
+                &mut DevmodeEngine::with_clock(config, clock),
+                &service,
+                startup,
+                idle(updates),
+            )
+            .unwrap();
+
+            service.actions().into_iter().any(
+                |call| matches!(call, ServiceCall::Broadcast(ref kind, _) if kind == "published"),
+            )
+        };
+
+        // "peer" leads at height 1, so this node only publishes once the
+        // fallback has passed.
+        assert!(!published(2));
+        assert!(published(8));
+    }
+
+    #[test]
+    fn rotation_follows_peers_connecting_and_disconnecting() {
+        let published_after_commit = |mut updates: Vec<Update>| {
+            let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+            let service = service_with_head(&genesis);
+            service.add_block(block("b1", "genesis", 1));
+            service.push_summarize_result(Err(Error::BlockNotReady));
+            service.push_summarize_result(Err(Error::BlockNotReady));
+            let config = DevmodeConfig {
+                leader_rotation: true,
+                leader_fallback: time::Duration::from_secs(5),
+                ..DevmodeConfig::default()
+            };
+            let clock = Arc::new(ManualClock::with_tick(time::Duration::from_secs(1)));
+
+            updates.push(Update::BlockCommit(b"b1".to_vec()));
+            updates.extend(idle(3));
+            run_updates(
+                &mut DevmodeEngine::with_clock(config, clock),
+                &service,
+                startup_state(genesis),
+                updates,
+            )
+            .unwrap();
+
+            service.actions().into_iter().any(
+                |call| matches!(call, ServiceCall::Broadcast(ref kind, _) if kind == "published"),
+            )
+        };
+        let early = || b"early".to_vec();
+
+        // Nothing is published for height 1 before b1 is committed. Once
+        // "early" has connected, it leads at height 2, so this node holds its
+        // block back for the fallback; once it has disconnected again, this
+        // node leads on its own.
+        assert!(!published_after_commit(vec![
+            no_op(),
+            Update::PeerConnected(PeerInfo { peer_id: early() }),
+        ]));
+        assert!(published_after_commit(vec![
+            Update::PeerConnected(PeerInfo { peer_id: early() }),
+            Update::PeerDisconnected(early()),
+        ]));
+    }
+
+    #[test]
+    fn only_the_designated_publisher_builds_blocks() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let service = service_with_head(&genesis);
+        service.add_block(block("b1", "genesis", 1));
+        let config = DevmodeConfig {
+            publisher: Some(PeerKey(b"other".to_vec())),
+            ..DevmodeConfig::default()
+        };
+
+        run_updates(
+            &mut DevmodeEngine::with_clock(
+                config,
+                Arc::new(ManualClock::with_tick(time::Duration::from_secs(1))),
+            ),
+            &service,
+            startup_state(genesis),
+            vec![
+                Update::BlockNew(block("b1", "genesis", 1)),
+                Update::BlockValid(b"b1".to_vec()),
+                Update::BlockCommit(b"b1".to_vec()),
+                no_op(),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            service.actions(),
+            vec![
+                ServiceCall::CheckBlocks(vec![b"b1".to_vec()]),
+                ServiceCall::SendTo(b"signer".to_vec(), "received".into(), b"b1".to_vec()),
+                ServiceCall::CommitBlock(b"b1".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn published_block_carries_legacy_consensus_by_default() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let service = service_with_head(&genesis);
+        service.push_summarize_result(Ok(b"summary".to_vec()));
+
+        run_updates(
+            &mut engine(&Arc::new(ManualClock::with_tick(
+                time::Duration::from_secs(1),
+            ))),
+            &service,
+            startup_state(genesis),
+            idle(1),
+        )
+        .unwrap();
+
+        assert!(service
+            .actions()
+            .contains(&ServiceCall::FinalizeBlock(b"Devmodesummary".to_vec())));
+    }
+
+    #[test]
+    fn published_block_carries_versioned_consensus() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let service = service_with_head(&genesis);
+        service.push_summarize_result(Ok(b"summary".to_vec()));
+
+        run_updates(
+            &mut DevmodeEngine::with_clock(
+                DevmodeConfig {
+                    versioned_payload: true,
+                    ..DevmodeConfig::default()
+                },
+                Arc::new(ManualClock::with_tick(time::Duration::from_secs(1))),
+            ),
+            &service,
+            startup_state(genesis),
+            idle(1),
+        )
+        .unwrap();
+
+        let data = service
+            .actions()
+            .into_iter()
+            .filter_map(|call| match call {
+                ServiceCall::FinalizeBlock(data) => Some(data),
+                _ => None,
+            })
+            .next()
+            .unwrap();
+        let payload = match ConsensusPayload::parse(&data).unwrap() {
+            ConsensusPayload::V1(payload) => payload,
+            payload => panic!("Unexpected payload {:?}", payload),
+        };
+        assert_eq!(payload.summary, b"summary".to_vec());
+        assert_eq!(payload.wait_time, Some(0));
+        assert!(payload.published_at.unwrap() > WALL_CLOCK_START * 1000);
+        assert_eq!(
+            payload.software_version,
+            Some(env!("CARGO_PKG_VERSION").to_string())
+        );
+        assert_eq!(payload.signature, None);
+    }
+
+    #[test]
+    fn unsigned_consensus_must_match_summary_exactly() {
+        let clock = Arc::new(ManualClock::new());
+        let mut format = ConsensusFormat {
+            clock,
+            signer: None,
+            versioned: false,
+            max_clock_skew: None,
+            enforce_min_wait_time: false,
+        };
+        let mut b1 = block("b1", "genesis", 1);
+        b1.summary = b"summary".to_vec();
+        b1.payload = format
+            .create(&b1.summary, time::Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(b1.payload, b"Devmodesummary".to_vec());
+        assert!(format.check(&b1));
+
+        b1.payload.extend_from_slice(b"signature");
+        assert!(!format.check(&b1));
+
+        b1.payload = b"Devmodesum".to_vec();
+        assert!(!format.check(&b1));
+
+        // Both formats are accepted, whichever one this node writes.
+        format.versioned = true;
+        b1.payload = format
+            .create(&b1.summary, time::Duration::from_secs(1))
+            .unwrap();
+        assert!(format.check(&b1));
+        b1.payload = b"Devmodesummary".to_vec();
+        assert!(format.check(&b1));
+
+        b1.payload = format
+            .create(b"other", time::Duration::from_secs(1))
+            .unwrap();
+        assert!(!format.check(&b1));
+
+        b1.payload = PayloadV1 {
+            signature: Some(b"signature".to_vec()),
+            ..PayloadV1::new(b1.summary.clone())
+        }
+        .encode();
+        assert!(!format.check(&b1));
+    }
+
+    #[test]
+    fn signed_consensus_must_be_signed_by_the_block_signer() {
+        let signed_format = |key: &str, versioned: bool| ConsensusFormat {
+            clock: Arc::new(ManualClock::new()),
+            signer: Some(PayloadSigner::from_hex(key).unwrap()),
+            versioned,
+            max_clock_skew: None,
+            enforce_min_wait_time: false,
+        };
+        let public_key = PayloadSigner::from_hex(TEST_KEY)
+            .unwrap()
+            .public_key()
+            .to_vec();
+
+        for &versioned in &[false, true] {
+            let format = signed_format(TEST_KEY, versioned);
+            let mut b1 = block("b1", "genesis", 1);
+            b1.summary = b"summary".to_vec();
+            b1.signer_id = public_key.clone();
+            b1.payload = format
+                .create(&b1.summary, time::Duration::from_secs(1))
+                .unwrap();
+            assert!(format.check(&b1));
+
+            let mut forged = b1.clone();
+            let last = forged.payload.len() - 1;
+            forged.payload[last] ^= 1;
+            assert!(!format.check(&forged));
+
+            let mut tampered = b1.clone();
+            tampered.summary = b"tampered".to_vec();
+            assert!(!format.check(&tampered));
+
+            let mut other_signer = b1.clone();
+            other_signer.payload = signed_format(OTHER_KEY, versioned)
+                .create(&b1.summary, time::Duration::from_secs(1))
+                .unwrap();
+            assert!(!format.check(&other_signer));
+
+            let mut unsigned = b1.clone();
+            unsigned.payload = ConsensusFormat {
+                signer: None,
+                ..signed_format(TEST_KEY, versioned)
+            }
+            .create(&b1.summary, time::Duration::from_secs(1))
+            .unwrap();
+            assert!(!format.check(&unsigned));
+        }
+    }
+
+    #[test]
+    fn blocks_must_be_published_between_parent_and_clock_skew() {
+        let published_at = |seconds: u64| {
+            PayloadV1 {
+                published_at: Some((WALL_CLOCK_START + seconds) * 1000),
+                ..PayloadV1::new(vec![])
+            }
+            .encode()
+        };
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let mut b1 = block("b1", "genesis", 1);
+        b1.payload = published_at(10);
+        let service = service_with_head(&genesis);
+        service.add_block(genesis.clone());
+        service.add_block(b1.clone());
+
+        let mut on_time = block("b2", "b1", 2);
+        on_time.payload = published_at(14);
+        let mut too_early = block("c2", "b1", 2);
+        too_early.payload = published_at(9);
+        let mut too_late = block("d2", "b1", 2);
+        too_late.payload = published_at(16);
+        let untimed = block("e2", "b1", 2);
+
+        let clock = Arc::new(ManualClock::new());
+        clock.advance(time::Duration::from_secs(10));
+        run_updates(
+            &mut DevmodeEngine::with_clock(
+                DevmodeConfig {
+                    max_clock_skew: Some(time::Duration::from_secs(5)),
+                    ..DevmodeConfig::default()
+                },
+                clock,
+            ),
+            &service,
+            startup_state(genesis),
+            vec![
+                Update::BlockNew(b1),
+                Update::BlockNew(on_time),
+                Update::BlockNew(too_early),
+                Update::BlockNew(too_late),
+                Update::BlockNew(untimed),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            decisions(&service),
+            vec![
+                ServiceCall::CheckBlocks(vec![b"b1".to_vec()]),
+                ServiceCall::CheckBlocks(vec![b"b2".to_vec()]),
+                ServiceCall::FailBlock(b"c2".to_vec()),
+                ServiceCall::FailBlock(b"d2".to_vec()),
+                ServiceCall::FailBlock(b"e2".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn block_with_missing_parent_is_ignored_when_checking_times() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let service = service_with_head(&genesis);
+        let mut orphan = block("x2", "x1", 2);
+        orphan.payload = PayloadV1 {
+            published_at: Some(WALL_CLOCK_START * 1000),
+            ..PayloadV1::new(vec![])
+        }
+        .encode();
+
+        run_updates(
+            &mut DevmodeEngine::with_clock(
+                DevmodeConfig {
+                    max_clock_skew: Some(time::Duration::from_secs(5)),
+                    ..DevmodeConfig::default()
+                },
+                Arc::new(ManualClock::new()),
+            ),
+            &service,
+            startup_state(genesis),
+            vec![Update::BlockNew(orphan)],
+        )
+        .unwrap();
+
+        assert_eq!(
+            decisions(&service),
+            vec![ServiceCall::IgnoreBlock(b"x2".to_vec())]
+        );
+    }
+
+    #[test]
+    fn blocks_published_before_min_wait_time_are_failed() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let mut b1 = block("b1", "genesis", 1);
+        b1.payload = PayloadV1 {
+            published_at: Some(10_000),
+            ..PayloadV1::new(vec![])
+        }
+        .encode();
+        let service = service_with_head(&genesis);
+        service.set_setting("sawtooth.consensus.min_wait_time", "2");
+        service.set_setting("sawtooth.consensus.max_wait_time", "2");
+        service.add_block(b1.clone());
+
+        let mut patient = block("b2", "b1", 2);
+        patient.payload = PayloadV1 {
+            published_at: Some(12_000),
+            ..PayloadV1::new(vec![])
+        }
+        .encode();
+        let mut hasty = block("c2", "b1", 2);
+        hasty.payload = PayloadV1 {
+            published_at: Some(11_999),
+            ..PayloadV1::new(vec![])
+        }
+        .encode();
+
+        run_updates(
+            &mut DevmodeEngine::with_clock(
+                DevmodeConfig {
+                    enforce_min_wait_time: true,
+                    ..DevmodeConfig::default()
+                },
+                Arc::new(ManualClock::new()),
+            ),
+            &service,
+            startup_state(genesis),
+            vec![Update::BlockNew(patient), Update::BlockNew(hasty)],
+        )
+        .unwrap();
+
+        assert_eq!(
+            decisions(&service),
+            vec![
+                ServiceCall::CheckBlocks(vec![b"b2".to_vec()]),
+                ServiceCall::FailBlock(b"c2".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn inverted_wait_time_range_allows_publishing_right_after_the_parent() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let mut b1 = block("b1", "genesis", 1);
+        b1.payload = PayloadV1 {
+            published_at: Some(10_000),
+            ..PayloadV1::new(vec![])
+        }
+        .encode();
+        let service = service_with_head(&genesis);
+        service.set_setting("sawtooth.consensus.min_wait_time", "5");
+        service.set_setting("sawtooth.consensus.max_wait_time", "2");
+        service.add_block(b1.clone());
+
+        // The schedule publishes right away when the minimum is over the
+        // maximum, so the check mustn't hold blocks to the minimum either.
+        let mut b2 = block("b2", "b1", 2);
+        b2.payload = PayloadV1 {
+            published_at: Some(10_000),
+            ..PayloadV1::new(vec![])
+        }
+        .encode();
+
+        run_updates(
+            &mut DevmodeEngine::with_clock(
+                DevmodeConfig {
+                    enforce_min_wait_time: true,
+                    ..DevmodeConfig::default()
+                },
+                Arc::new(ManualClock::new()),
+            ),
+            &service,
+            startup_state(genesis),
+            vec![Update::BlockNew(b2)],
+        )
+        .unwrap();
+
+        assert_eq!(
+            decisions(&service),
+            vec![ServiceCall::CheckBlocks(vec![b"b2".to_vec()])]
+        );
+    }
+
+    #[test]
+    fn invalid_published_block_is_replaced() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let service = service_with_head(&genesis);
+        let mut published = block("published-1", "genesis", 1);
+        published.signer_id = b"local".to_vec();
+
+        let mut updates = idle(1);
+        updates.extend(vec![
+            Update::BlockNew(published),
+            Update::BlockNew(block("b1", "genesis", 1)),
+            Update::BlockInvalid(b"b1".to_vec()),
+            Update::BlockInvalid(b"published-1".to_vec()),
+        ]);
+        run_updates(
+            &mut engine(&Arc::new(ManualClock::with_tick(
+                time::Duration::from_secs(1),
+            ))),
+            &service,
+            startup_state(genesis),
+            updates,
+        )
+        .unwrap();
+
+        // Another node's invalid block changes nothing, but this node's own
+        // is replaced by a new block straight away.
+        assert_eq!(
+            actions_without_consensus(&service),
+            vec![
+                ServiceCall::InitializeBlock(None),
+                ServiceCall::SummarizeBlock,
+                ServiceCall::FinalizeBlock(vec![]),
+                ServiceCall::Broadcast("published".into(), b"published-1".to_vec()),
+                ServiceCall::CheckBlocks(vec![b"published-1".to_vec()]),
+                ServiceCall::CheckBlocks(vec![b"b1".to_vec()]),
+                ServiceCall::CancelBlock,
+                ServiceCall::InitializeBlock(None),
+                ServiceCall::SummarizeBlock,
+                ServiceCall::FinalizeBlock(vec![]),
+                ServiceCall::Broadcast("published".into(), b"published-2".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn misbehaving_signer_is_ignored_over_the_threshold() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let service = service_with_head(&genesis);
+        let bad_block = |id: &str, payload: &[u8]| {
+            let mut block = block(id, "genesis", 1);
+            block.signer_id = b"bad".to_vec();
+            block.payload = payload.to_vec();
+            block
+        };
+        let mut unknown = PeerMessage::default();
+        unknown.header.message_type = "bogus".into();
+
+        run_updates(
+            &mut DevmodeEngine::with_clock(
+                DevmodeConfig {
+                    misbehaviour_threshold: Some(2),
+                    ..DevmodeConfig::default()
+                },
+                Arc::new(ManualClock::new()),
+            ),
+            &service,
+            startup_state(genesis),
+            vec![
+                Update::PeerMessage(unknown, b"bad".to_vec()),
+                Update::BlockNew(bad_block("b1", b"Bogus")),
+                Update::BlockNew(bad_block("b2", b"Devmode")),
+                Update::BlockNew(bad_block("b3", b"Bogus")),
+                Update::BlockNew(bad_block("b4", b"Devmode")),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            decisions(&service),
+            vec![
+                ServiceCall::FailBlock(b"b1".to_vec()),
+                ServiceCall::CheckBlocks(vec![b"b2".to_vec()]),
+                ServiceCall::FailBlock(b"b3".to_vec()),
+                ServiceCall::IgnoreBlock(b"b4".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn late_blocks_and_local_blocks_do_not_count_as_misbehaviour() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let service = service_with_head(&genesis);
+        service.add_block(genesis.clone());
+        let signed_block = |id: &str, signer_id: &[u8], payload: Vec<u8>| {
+            let mut block = block(id, "genesis", 1);
+            block.signer_id = signer_id.to_vec();
+            block.payload = payload;
+            block
+        };
+        let published_at = |seconds: u64| {
+            PayloadV1 {
+                published_at: Some((WALL_CLOCK_START + seconds) * 1000),
+                ..PayloadV1::new(vec![])
+            }
+            .encode()
+        };
+
+        run_updates(
+            &mut DevmodeEngine::with_clock(
+                DevmodeConfig {
+                    max_clock_skew: Some(time::Duration::from_secs(5)),
+                    misbehaviour_threshold: Some(0),
+                    ..DevmodeConfig::default()
+                },
+                Arc::new(ManualClock::new()),
+            ),
+            &service,
+            startup_state(genesis),
+            vec![
+                Update::BlockNew(signed_block("b1", b"skewed", published_at(60))),
+                Update::BlockNew(signed_block("c1", b"skewed", published_at(0))),
+                Update::BlockNew(signed_block("d1", b"local", b"Bogus".to_vec())),
+                Update::BlockNew(signed_block("e1", b"local", published_at(0))),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            decisions(&service),
+            vec![
+                ServiceCall::FailBlock(b"b1".to_vec()),
+                ServiceCall::CheckBlocks(vec![b"c1".to_vec()]),
+                ServiceCall::FailBlock(b"d1".to_vec()),
+                ServiceCall::CheckBlocks(vec![b"e1".to_vec()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn lost_connection_is_only_an_error_when_reconnecting() {
+        use std::sync::mpsc::channel;
+
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        for &reconnect in &[false, true] {
+            let (_, updates) = channel();
+            let result = DevmodeEngine::with_clock(
+                DevmodeConfig {
+                    reconnect,
+                    ..DevmodeConfig::default()
+                },
+                Arc::new(ManualClock::new()),
+            )
+            .start(
+                updates,
+                Box::new(service_with_head(&genesis)),
+                startup_state(genesis.clone()),
+            );
+            assert_eq!(result.is_err(), reconnect);
+        }
+    }
+
+    #[test]
+    fn signing_key_must_belong_to_the_validator() {
+        use std::fs;
+        use std::sync::mpsc::channel;
+
+        let key_file = std::env::temp_dir().join("devmode-signing-key-test.priv");
+        fs::write(&key_file, TEST_KEY).unwrap();
+        let public_key = PayloadSigner::from_hex(TEST_KEY)
+            .unwrap()
+            .public_key()
+            .to_vec();
+
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        for &(ref local_id, belongs) in &[(b"local".to_vec(), false), (public_key, true)] {
+            let (_, updates) = channel();
+            let mut startup_state = startup_state(genesis.clone());
+            startup_state.local_peer_info.peer_id = local_id.clone();
+            let result = DevmodeEngine::with_clock(
+                DevmodeConfig {
+                    signing_key_file: Some(key_file.clone()),
+                    ..DevmodeConfig::default()
+                },
+                Arc::new(ManualClock::new()),
+            )
+            .run(
+                updates,
+                Box::new(service_with_head(&genesis)),
+                startup_state,
+            );
+
+            // With the right key, the engine runs until it finds the
+            // validator gone.
+            match result {
+                Err(Error::InvalidState(_)) => assert!(!belongs),
+                Err(Error::ReceiveError(_)) => assert!(belongs),
+                _ => panic!("Unexpected result"),
+            }
+        }
+        fs::remove_file(&key_file).unwrap();
+    }
+
+    #[test]
+    fn valid_block_with_missing_ancestor_is_ignored() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let service = service_with_head(&genesis);
+        service.add_block(block("x2", "x1", 2));
+
+        run_updates(
+            &mut engine(&Arc::new(ManualClock::new())),
+            &service,
+            startup_state(genesis),
+            vec![Update::BlockValid(b"x2".to_vec())],
+        )
+        .unwrap();
+
+        assert_eq!(
+            decisions(&service),
+            vec![ServiceCall::IgnoreBlock(b"x2".to_vec())]
+        );
+    }
+
+    #[test]
+    fn unknown_block_does_not_stop_the_engine() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let service = service_with_head(&genesis);
+
+        run_updates(
+            &mut engine(&Arc::new(ManualClock::new())),
+            &service,
+            startup_state(genesis),
+            vec![
+                Update::BlockValid(b"missing".to_vec()),
+                Update::BlockNew(block("b1", "genesis", 1)),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            decisions(&service),
+            vec![
+                ServiceCall::IgnoreBlock(b"missing".to_vec()),
+                ServiceCall::CheckBlocks(vec![b"b1".to_vec()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn compare_forks_finds_common_ancestor_in_batches() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let b1 = block("b1", "genesis", 1);
+        let b2 = block("b2", "b1", 2);
+        let b3 = block("b3", "b2", 3);
+        let a2 = block("a2", "b1", 2);
+        let a3 = block("a3", "a2", 3);
+        let a4 = block("a4", "a3", 4);
+        let service = service_with_head(&b3);
+        for block in [genesis, b1.clone(), b2.clone(), a2, a3] {
+            service.add_block(block);
+        }
+        let mut devmode_service = DevmodeService::new(Box::new(service.clone()));
+
+        let comparison = compare_forks(&mut devmode_service, &b3, &a4).unwrap();
+
+        assert_eq!(comparison.ancestor, b1);
+        assert_eq!(comparison.chain_block, None);
+        assert_eq!(comparison.chain_depth, 2);
+        assert_eq!(comparison.fork_depth, 3);
+        assert_eq!(
+            service.calls(),
+            vec![
+                ServiceCall::GetBlocks(vec![b"a3".to_vec()]),
+                ServiceCall::GetBlocks(vec![b"b2".to_vec(), b"a2".to_vec()]),
+                ServiceCall::GetBlocks(vec![b"b1".to_vec()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn compare_forks_reports_missing_ancestor() {
+        let b2 = block("b2", "b1", 2);
+        let a2 = block("a2", "a1", 2);
+        let service = service_with_head(&b2);
+        let mut devmode_service = DevmodeService::new(Box::new(service));
+
+        match compare_forks(&mut devmode_service, &b2, &a2) {
+            Err(DevmodeError::UnknownBlock(_)) => {}
+            _ => panic!("Expected UnknownBlock"),
+        }
+    }
+
+    #[test]
+    fn known_blocks_and_chain_head_are_not_refetched() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let b1 = block("b1", "genesis", 1);
+        let service = service_with_head(&genesis);
+        service.add_block(b1.clone());
+
+        run_updates(
+            &mut engine(&Arc::new(ManualClock::new())),
+            &service,
+            startup_state(genesis),
+            vec![Update::BlockNew(b1), Update::BlockValid(b"b1".to_vec())],
+        )
+        .unwrap();
+
+        assert!(!service
+            .calls()
+            .iter()
+            .any(|call| matches!(*call, ServiceCall::GetBlocks(_) | ServiceCall::GetChainHead)));
+        assert_eq!(
+            decisions(&service),
+            vec![
+                ServiceCall::CheckBlocks(vec![b"b1".to_vec()]),
+                ServiceCall::CommitBlock(b"b1".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn settings_are_fetched_once_per_block() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let service = service_with_head(&genesis);
+        service.add_block(block("b1", "genesis", 1));
+        service.add_block(block("b2", "b1", 2));
+
+        run_updates(
+            &mut engine(&Arc::new(ManualClock::new())),
+            &service,
+            startup_state(genesis),
+            vec![
+                Update::BlockCommit(b"b1".to_vec()),
+                Update::BlockCommit(b"b2".to_vec()),
+                // A head that was already seen isn't fetched again.
+                Update::BlockCommit(b"b1".to_vec()),
+            ],
+        )
+        .unwrap();
+
+        let fetched_at: Vec<BlockId> = service
+            .calls()
+            .into_iter()
+            .filter_map(|call| match call {
+                ServiceCall::GetSettings(block_id, _) => Some(block_id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            fetched_at,
+            vec![b"genesis".to_vec(), b"b1".to_vec(), b"b2".to_vec()]
+        );
+    }
+
+    #[test]
+    fn failed_settings_fetch_falls_back_to_last_known() {
+        let genesis = block("genesis", "\0\0\0\0\0\0\0\0", 0);
+        let mock = service_with_head(&genesis);
+        mock.set_setting("sawtooth.consensus.max_wait_time", "30");
+        let mut service = DevmodeService::new(Box::new(mock.clone()));
+
+        let known = service.get_settings(&genesis);
+        assert_eq!(known.max_wait_time, time::Duration::from_secs(30));
+
+        mock.push_settings_error(Error::ReceiveError("timed out".into()));
+        assert_eq!(service.get_settings(&block("b1", "genesis", 1)), known);
     }
 }
@@ -0,0 +1,86 @@
+/*
+ * Copyright 2018 Intel Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+use std::error;
+use std::fmt;
+
+use sawtooth_sdk::consensus::engine::Error;
+
+#[derive(Debug)]
+pub enum DevmodeError {
+    /// The connection to the validator is gone; the engine cannot continue.
+    Disconnected(String),
+    /// The validator does not know a block the engine referred to.
+    UnknownBlock(String),
+    /// The validator refused a request in its current state.
+    InvalidState(String),
+    /// A message could not be delivered to a peer, e.g. because it left.
+    UnknownPeer(String),
+    /// A single request to the validator failed, but the connection is
+    /// still usable.
+    RequestFailed(String),
+}
+
+impl DevmodeError {
+    /// Wrap an error returned by the consensus service while trying to
+    /// perform `action`.
+    pub fn from_service(action: &str, err: Error) -> Self {
+        let msg = format!("Failed to {}: {}", action, err);
+        match err {
+            Error::SendError(_) => DevmodeError::Disconnected(msg),
+            Error::UnknownBlock(_) => DevmodeError::UnknownBlock(msg),
+            Error::InvalidState(_) | Error::NoChainHead | Error::BlockNotReady => {
+                DevmodeError::InvalidState(msg)
+            }
+            Error::UnknownPeer(_) => DevmodeError::UnknownPeer(msg),
+            Error::ReceiveError(_) | Error::EncodingError(_) => DevmodeError::RequestFailed(msg),
+        }
+    }
+
+    /// Whether the engine has to stop after this error. Anything else only
+    /// affects the update being handled, so the engine can log it and move
+    /// on.
+    pub fn is_fatal(&self) -> bool {
+        matches!(*self, DevmodeError::Disconnected(_))
+    }
+}
+
+impl fmt::Display for DevmodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DevmodeError::Disconnected(ref msg)
+            | DevmodeError::UnknownBlock(ref msg)
+            | DevmodeError::InvalidState(ref msg)
+            | DevmodeError::UnknownPeer(ref msg)
+            | DevmodeError::RequestFailed(ref msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl error::Error for DevmodeError {}
+
+impl From<DevmodeError> for Error {
+    fn from(err: DevmodeError) -> Self {
+        match err {
+            DevmodeError::Disconnected(msg) => Error::SendError(msg),
+            DevmodeError::UnknownBlock(msg) => Error::UnknownBlock(msg),
+            DevmodeError::InvalidState(msg) => Error::InvalidState(msg),
+            DevmodeError::UnknownPeer(msg) => Error::UnknownPeer(msg),
+            DevmodeError::RequestFailed(msg) => Error::ReceiveError(msg),
+        }
+    }
+}
@@ -0,0 +1,248 @@
+/*
+ * Copyright 2018 Intel Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+use std::fmt;
+use std::str::FromStr;
+
+use sawtooth_sdk::consensus::engine::{Block, PeerId};
+
+/// Where a new block's branch meets the current chain.
+pub struct ForkComparison {
+    /// The most recent block both branches have in common.
+    pub ancestor: Block,
+    /// The block on the current chain at the new block's height, if the
+    /// current chain is at least that long.
+    pub chain_block: Option<Block>,
+    /// Number of blocks on the current chain after the ancestor.
+    pub chain_depth: u64,
+    /// Number of blocks on the new block's branch after the ancestor.
+    pub fork_depth: u64,
+}
+
+/// A valid block competing with the current chain head.
+pub struct Fork<'a> {
+    pub chain_head: &'a Block,
+    pub block: &'a Block,
+    pub comparison: &'a ForkComparison,
+}
+
+impl<'a> Fork<'a> {
+    /// The block on the current chain that `block` competes with directly.
+    fn competitor(&self) -> &'a Block {
+        self.comparison
+            .chain_block
+            .as_ref()
+            .unwrap_or(self.chain_head)
+    }
+}
+
+/// Decides whether the engine should switch to a new, valid block.
+pub trait ForkResolver {
+    /// Returns true if `fork.block` should be committed in place of the
+    /// current chain head, false if it should be ignored.
+    fn should_switch(&self, fork: &Fork) -> bool;
+}
+
+/// Higher block number wins, ties are broken by the larger block id.
+pub struct HighestIdWins;
+
+impl ForkResolver for HighestIdWins {
+    fn should_switch(&self, fork: &Fork) -> bool {
+        fork.block.block_num > fork.chain_head.block_num
+            || fork.block.block_id > fork.competitor().block_id
+    }
+}
+
+/// Higher block number wins, ties are broken by the smaller block id.
+pub struct LowestIdWins;
+
+impl ForkResolver for LowestIdWins {
+    fn should_switch(&self, fork: &Fork) -> bool {
+        fork.block.block_num > fork.chain_head.block_num
+            || fork.block.block_id < fork.competitor().block_id
+    }
+}
+
+/// The first block seen at a height wins; only a block that extends the chain
+/// beyond the current head is committed.
+pub struct FirstSeenWins;
+
+impl ForkResolver for FirstSeenWins {
+    fn should_switch(&self, fork: &Fork) -> bool {
+        fork.block.block_num > fork.chain_head.block_num
+    }
+}
+
+/// Blocks signed by the local validator win ties against other validators'
+/// blocks; everything else is resolved like `HighestIdWins`.
+pub struct PreferOwnBlocks {
+    local_id: PeerId,
+}
+
+impl PreferOwnBlocks {
+    pub fn new(local_id: PeerId) -> Self {
+        PreferOwnBlocks { local_id }
+    }
+}
+
+impl ForkResolver for PreferOwnBlocks {
+    fn should_switch(&self, fork: &Fork) -> bool {
+        if fork.block.block_num > fork.chain_head.block_num {
+            return true;
+        }
+
+        let own_block = fork.block.signer_id == self.local_id;
+        let own_competitor = fork.competitor().signer_id == self.local_id;
+        if own_block != own_competitor {
+            own_block
+        } else {
+            HighestIdWins.should_switch(fork)
+        }
+    }
+}
+
+/// The built-in fork resolution strategies, as selected on the command line or
+/// by the `sawtooth.consensus.devmode.fork_resolution` setting.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ForkResolution {
+    #[default]
+    HighestId,
+    LowestId,
+    FirstSeen,
+    PreferOwn,
+}
+
+impl ForkResolution {
+    pub const NAMES: &'static [&'static str] =
+        &["highest-id", "lowest-id", "first-seen", "prefer-own"];
+
+    pub fn resolver(self, local_id: &PeerId) -> Box<dyn ForkResolver> {
+        match self {
+            ForkResolution::HighestId => Box::new(HighestIdWins),
+            ForkResolution::LowestId => Box::new(LowestIdWins),
+            ForkResolution::FirstSeen => Box::new(FirstSeenWins),
+            ForkResolution::PreferOwn => Box::new(PreferOwnBlocks::new(local_id.clone())),
+        }
+    }
+}
+
+impl FromStr for ForkResolution {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "highest-id" => Ok(ForkResolution::HighestId),
+            "lowest-id" => Ok(ForkResolution::LowestId),
+            "first-seen" => Ok(ForkResolution::FirstSeen),
+            "prefer-own" => Ok(ForkResolution::PreferOwn),
+            _ => Err("Invalid fork resolution"),
+        }
+    }
+}
+
+impl fmt::Display for ForkResolution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ForkResolution::HighestId => "highest-id",
+            ForkResolution::LowestId => "lowest-id",
+            ForkResolution::FirstSeen => "first-seen",
+            ForkResolution::PreferOwn => "prefer-own",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(id: &str, block_num: u64, signer: &str) -> Block {
+        Block {
+            block_id: id.as_bytes().to_vec(),
+            block_num,
+            signer_id: signer.as_bytes().to_vec(),
+            ..Block::default()
+        }
+    }
+
+    fn comparison(chain_block: Option<&Block>) -> ForkComparison {
+        ForkComparison {
+            ancestor: block("ancestor", 0, "peer"),
+            chain_block: chain_block.cloned(),
+            chain_depth: 1,
+            fork_depth: 1,
+        }
+    }
+
+    fn switches(resolver: &dyn ForkResolver, chain_head: &Block, new: &Block) -> bool {
+        let comparison = comparison(if new.block_num == chain_head.block_num {
+            Some(chain_head)
+        } else {
+            None
+        });
+        resolver.should_switch(&Fork {
+            chain_head,
+            block: new,
+            comparison: &comparison,
+        })
+    }
+
+    #[test]
+    fn longer_chain_wins_except_first_seen_ties() {
+        let head = block("b", 1, "peer");
+        let longer = block("a", 2, "peer");
+        let tie = block("c", 1, "peer");
+
+        assert!(switches(&HighestIdWins, &head, &longer));
+        assert!(switches(&HighestIdWins, &head, &tie));
+        assert!(switches(&LowestIdWins, &head, &longer));
+        assert!(!switches(&LowestIdWins, &head, &tie));
+        assert!(switches(&FirstSeenWins, &head, &longer));
+        assert!(!switches(&FirstSeenWins, &head, &tie));
+    }
+
+    #[test]
+    fn own_blocks_win_ties() {
+        let resolver = PreferOwnBlocks::new(b"local".to_vec());
+        let theirs = block("b", 1, "peer");
+        let ours = block("a", 1, "local");
+
+        assert!(switches(&resolver, &theirs, &ours));
+        assert!(!switches(&resolver, &ours, &theirs));
+    }
+
+    #[test]
+    fn shorter_fork_competes_with_chain_block() {
+        let head = block("c", 3, "peer");
+        let comparison = comparison(Some(&block("b", 2, "peer")));
+        let fork = Fork {
+            chain_head: &head,
+            block: &block("a", 2, "peer"),
+            comparison: &comparison,
+        };
+
+        assert!(!HighestIdWins.should_switch(&fork));
+        assert!(LowestIdWins.should_switch(&fork));
+    }
+
+    #[test]
+    fn parses_names() {
+        for name in ForkResolution::NAMES {
+            assert_eq!(name.parse::<ForkResolution>().unwrap().to_string(), *name);
+        }
+        assert!("longest".parse::<ForkResolution>().is_err());
+    }
+}
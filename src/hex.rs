@@ -0,0 +1,61 @@
+/*
+ * Copyright 2018 Intel Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+//! Hex encoding for keys and signatures, which Sawtooth passes around as hex
+//! strings.
+
+/// Encode `bytes` as lowercase hex, two digits per byte.
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decode a non-empty hex string.
+pub fn decode(s: &str) -> Result<Vec<u8>, &'static str> {
+    if s.is_empty() {
+        return Err("Empty hex string");
+    }
+
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match *pair {
+            [high, low] => Ok(digit(high)? << 4 | digit(low)?),
+            _ => Err("Odd number of hex digits"),
+        })
+        .collect()
+}
+
+fn digit(c: u8) -> Result<u8, &'static str> {
+    (c as char)
+        .to_digit(16)
+        .map(|digit| digit as u8)
+        .ok_or("Invalid hex digit")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        assert_eq!(decode("02a0ff").unwrap(), vec![0x02, 0xa0, 0xff]);
+        assert_eq!(encode(&[0x02, 0xa0, 0xff]), "02a0ff");
+
+        for invalid in &["", "2a0ff", "02a0fg", "02a0é"] {
+            assert!(decode(invalid).is_err());
+        }
+    }
+}
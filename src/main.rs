@@ -17,23 +17,53 @@
 
 #[macro_use]
 extern crate clap;
+extern crate ctrlc;
 #[macro_use]
 extern crate log;
 extern crate log4rs;
 extern crate rand;
 extern crate sawtooth_sdk;
 
+mod cache;
+mod clock;
 mod engine;
+mod error;
+mod fork;
+mod hex;
+mod payload;
+mod peers;
+mod propagation;
+mod reputation;
+mod rotation;
+mod schedule;
+mod settings;
+mod signature;
+#[cfg(test)]
+mod test_support;
+mod validation;
 
+use std::cmp;
+use std::path::PathBuf;
 use std::process;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
 use log::LogLevelFilter;
 use log4rs::append::console::ConsoleAppender;
 use log4rs::config::{Appender, Config, Root};
 use log4rs::encode::pattern::PatternEncoder;
 
-use engine::DevmodeEngine;
-use sawtooth_sdk::consensus::zmq_driver::ZmqDriver;
+use engine::{DevmodeConfig, DevmodeEngine, DEFAULT_LEADER_FALLBACK};
+use fork::ForkResolution;
+use reputation::MisbehaviourAction;
+use sawtooth_sdk::consensus::zmq_driver::{Stop, ZmqDriver};
+use schedule::ScheduleMode;
+use signature::{PayloadSigner, DEFAULT_KEY_FILE};
+
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+// Clocks a day apart aren't skewed, they're wrong.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(24 * 60 * 60);
 
 fn main() {
     let matches = clap_app!(("devmode-engine-rust") =>
@@ -41,6 +71,43 @@ fn main() {
         (about: "Devmode Consensus Engine (Rust)")
         (@arg connect: -C --connect +takes_value
          "connection endpoint for validator")
+        (@arg reconnect: --reconnect
+         "reconnect to the validator if the connection is lost")
+        (@arg fork_resolution: --("fork-resolution") +takes_value
+         possible_values(ForkResolution::NAMES)
+         "how to choose between forks; overrides the on-chain setting")
+        (@arg publishing_schedule: --("publishing-schedule") +takes_value
+         possible_values(ScheduleMode::NAMES)
+         "how to choose the time to wait before publishing; overrides the on-chain setting")
+        (@arg publishing_seed: --("publishing-seed") +takes_value
+         "seed for random publishing wait times; overrides the on-chain setting")
+        (@arg skip_empty_blocks: --("skip-empty-blocks")
+         "don't publish a block until there are batches to put in it")
+        (@arg skip_empty_blocks_for: --("skip-empty-blocks-for") +takes_value requires[skip_empty_blocks]
+         "with --skip-empty-blocks, seconds at each height after which to check for batches every second instead")
+        (@arg announce_leaving: --("announce-leaving")
+         "tell peers when the engine shuts down")
+        (@arg leader_rotation: --("leader-rotation")
+         "take turns publishing with the other nodes")
+        (@arg leader_fallback: --("leader-fallback") +takes_value
+         "with --leader-rotation, seconds to wait for the leader before the next node publishes")
+        (@arg publisher: --publisher +takes_value
+         "public key of the only node that publishes blocks; overrides the on-chain setting")
+        (@arg sign_payload: --("sign-payload")
+         "sign consensus data with the validator key and require signed blocks")
+        (@arg key_file: --("key-file") +takes_value
+         "with --sign-payload, the validator private key file")
+        (@arg versioned_payload: --("versioned-payload")
+         "write consensus data in the versioned format, which older nodes reject")
+        (@arg max_clock_skew: --("max-clock-skew") +takes_value requires[versioned_payload]
+         "reject blocks published before their parent or more than this many seconds, up to a day, in the future")
+        (@arg enforce_min_wait_time: --("enforce-min-wait-time") requires[versioned_payload]
+         "reject blocks published sooner than the minimum wait time after their parent")
+        (@arg misbehaviour_threshold: --("misbehaviour-threshold") +takes_value
+         "misbehaviour score over which a peer's blocks are no longer checked")
+        (@arg misbehaviour_action: --("misbehaviour-action") +takes_value
+         possible_values(MisbehaviourAction::NAMES) requires[misbehaviour_threshold]
+         "what to do with blocks from peers over the misbehaviour threshold")
         (@arg verbose: -v --verbose +multiple
          "increase output verbosity"))
     .get_matches();
@@ -76,11 +143,155 @@ fn main() {
         process::exit(1);
     });
 
-    let (driver, _stop) = ZmqDriver::new();
-    driver
-        .start(endpoint, DevmodeEngine::new())
-        .unwrap_or_else(|err| {
+    let reconnect = matches.is_present("reconnect");
+    let config = DevmodeConfig {
+        reconnect,
+        fork_resolution: matches
+            .value_of("fork_resolution")
+            .map(|fork_resolution| fork_resolution.parse().expect("Validated by clap")),
+        publishing_schedule: matches
+            .value_of("publishing_schedule")
+            .map(|schedule| schedule.parse().expect("Validated by clap")),
+        publishing_seed: matches.value_of("publishing_seed").map(|seed| {
+            seed.parse().unwrap_or_else(|_| {
+                error!("Invalid publishing seed: {}", seed);
+                process::exit(1);
+            })
+        }),
+        skip_empty_blocks: matches.is_present("skip_empty_blocks"),
+        skip_empty_blocks_for: matches.value_of("skip_empty_blocks_for").map(|limit| {
+            limit.parse().map(Duration::from_secs).unwrap_or_else(|_| {
+                error!("Invalid time limit for skipping empty blocks: {}", limit);
+                process::exit(1);
+            })
+        }),
+        announce_leaving: matches.is_present("announce_leaving"),
+        leader_rotation: matches.is_present("leader_rotation"),
+        leader_fallback: matches
+            .value_of("leader_fallback")
+            .map(|fallback| {
+                fallback
+                    .parse()
+                    .map(Duration::from_secs)
+                    .unwrap_or_else(|_| {
+                        error!("Invalid leader fallback: {}", fallback);
+                        process::exit(1);
+                    })
+            })
+            .unwrap_or(DEFAULT_LEADER_FALLBACK),
+        publisher: matches.value_of("publisher").map(|publisher| {
+            publisher.parse().unwrap_or_else(|_| {
+                error!("Invalid publisher public key: {}", publisher);
+                process::exit(1);
+            })
+        }),
+        signing_key_file: if matches.is_present("sign_payload") {
+            Some(PathBuf::from(
+                matches.value_of("key_file").unwrap_or(DEFAULT_KEY_FILE),
+            ))
+        } else {
+            None
+        },
+        versioned_payload: matches.is_present("versioned_payload"),
+        max_clock_skew: matches.value_of("max_clock_skew").map(|skew| {
+            match skew.parse().map(Duration::from_secs) {
+                Ok(skew) if skew <= MAX_CLOCK_SKEW => skew,
+                _ => {
+                    error!(
+                        "Invalid maximum clock skew: {}; must be at most {} seconds",
+                        skew,
+                        MAX_CLOCK_SKEW.as_secs()
+                    );
+                    process::exit(1);
+                }
+            }
+        }),
+        enforce_min_wait_time: matches.is_present("enforce_min_wait_time"),
+        misbehaviour_threshold: matches.value_of("misbehaviour_threshold").map(|threshold| {
+            threshold.parse().unwrap_or_else(|_| {
+                error!("Invalid misbehaviour threshold: {}", threshold);
+                process::exit(1);
+            })
+        }),
+        misbehaviour_action: matches
+            .value_of("misbehaviour_action")
+            .map(|action| action.parse().expect("Validated by clap"))
+            .unwrap_or_default(),
+    };
+
+    // Check the key once up front, rather than failing on every connection.
+    if let Some(ref path) = config.signing_key_file {
+        if let Err(err) = PayloadSigner::from_key_file(path) {
             error!("{}", err);
             process::exit(1);
+        }
+    }
+
+    // On SIGINT or SIGTERM, stop the driver of the current connection so the
+    // engine can shut down cleanly, and don't reconnect. The condvar wakes the
+    // main thread if it is waiting to reconnect.
+    let shutting_down = Arc::new((Mutex::new(false), Condvar::new()));
+    let current_stop: Arc<Mutex<Option<Stop>>> = Arc::new(Mutex::new(None));
+    {
+        let shutting_down = shutting_down.clone();
+        let current_stop = current_stop.clone();
+        ctrlc::set_handler(move || {
+            info!("Received signal, shutting down");
+            let (ref flag, ref signal) = *shutting_down;
+            *flag.lock().unwrap() = true;
+            signal.notify_all();
+            if let Some(ref stop) = *current_stop.lock().unwrap() {
+                stop.stop();
+            }
+        })
+        .unwrap_or_else(|err| {
+            error!("Failed to set signal handler: {}", err);
+            process::exit(1);
         });
+    }
+
+    let (ref shutdown_flag, ref shutdown_signal) = *shutting_down;
+    let mut reconnect_delay = INITIAL_RECONNECT_DELAY;
+
+    while !*shutdown_flag.lock().unwrap() {
+        let (driver, stop) = ZmqDriver::new();
+        *current_stop.lock().unwrap() = Some(stop);
+        let connected_at = Instant::now();
+
+        let err = match driver.start(endpoint, DevmodeEngine::new(config.clone())) {
+            Ok(()) => break,
+            Err(err) => err,
+        };
+
+        if *shutdown_flag.lock().unwrap() {
+            break;
+        }
+        // Without --reconnect, the engine logs its own errors and stops, but
+        // the driver still returns an error if it couldn't start the engine
+        // or lost the validator. Exit with it, as before --reconnect existed.
+        if !reconnect {
+            error!("{}", err);
+            process::exit(1);
+        }
+
+        // Only back off further if the last connection didn't last long
+        // enough to be considered successful.
+        if connected_at.elapsed() > reconnect_delay {
+            reconnect_delay = INITIAL_RECONNECT_DELAY;
+        }
+
+        warn!(
+            "{}; reconnecting to {} in {:?}",
+            err, endpoint, reconnect_delay
+        );
+        let (down, _) = shutdown_signal
+            .wait_timeout_while(shutdown_flag.lock().unwrap(), reconnect_delay, |down| {
+                !*down
+            })
+            .unwrap();
+        if *down {
+            break;
+        }
+        reconnect_delay = cmp::min(reconnect_delay * 2, MAX_RECONNECT_DELAY);
+    }
 }
@@ -0,0 +1,249 @@
+/*
+ * Copyright 2018 Intel Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+//! The consensus data devmode puts in each block's payload.
+//!
+//! The legacy format is `Devmode` followed by the block summary and, when
+//! payloads are signed, a signature over the summary. Versioned payloads start
+//! with `DEVMODE` and a version byte. Version 1 is a list of fields, each a
+//! one-byte tag, a two-byte big-endian length and the value; fields with
+//! unknown tags are skipped so that new ones can be added without a new
+//! version. The signature, if any, is the last field and covers everything
+//! before it.
+
+use std::str;
+
+pub const LEGACY_MAGIC: &[u8] = b"Devmode";
+pub const MAGIC: &[u8] = b"DEVMODE";
+pub const VERSION: u8 = 1;
+
+const SUMMARY: u8 = 1;
+const PUBLISHED_AT: u8 = 2;
+const WAIT_TIME: u8 = 3;
+const SOFTWARE_VERSION: u8 = 4;
+const SIGNATURE: u8 = 0xff;
+
+// Tag and length
+const FIELD_HEADER_LEN: usize = 3;
+
+/// A block's consensus data, in whichever format it was written.
+#[derive(Debug, PartialEq)]
+pub enum ConsensusPayload {
+    /// Everything after the legacy magic: the summary, possibly followed by
+    /// a signature.
+    Legacy(Vec<u8>),
+    V1(PayloadV1),
+}
+
+impl ConsensusPayload {
+    pub fn parse(bytes: &[u8]) -> Result<Self, &'static str> {
+        if bytes.starts_with(MAGIC) {
+            match bytes.get(MAGIC.len()) {
+                Some(&VERSION) => {
+                    PayloadV1::parse(&bytes[MAGIC.len() + 1..]).map(ConsensusPayload::V1)
+                }
+                Some(_) => Err("Unsupported payload version"),
+                None => Err("Missing payload version"),
+            }
+        } else if bytes.starts_with(LEGACY_MAGIC) {
+            Ok(ConsensusPayload::Legacy(
+                bytes[LEGACY_MAGIC.len()..].to_vec(),
+            ))
+        } else {
+            Err("Not a devmode payload")
+        }
+    }
+
+    /// When the block was published, in milliseconds since the Unix epoch,
+    /// if the payload says.
+    pub fn published_at(&self) -> Option<u64> {
+        match *self {
+            ConsensusPayload::Legacy(_) => None,
+            ConsensusPayload::V1(ref payload) => payload.published_at,
+        }
+    }
+}
+
+/// Version 1 consensus data. Everything but the summary is optional.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PayloadV1 {
+    pub summary: Vec<u8>,
+    /// When the block was published, in milliseconds since the Unix epoch.
+    pub published_at: Option<u64>,
+    /// The time the publisher waited before publishing, in milliseconds.
+    pub wait_time: Option<u64>,
+    /// The version of the engine that published the block.
+    pub software_version: Option<String>,
+    /// The publisher's signature over the rest of the payload.
+    pub signature: Option<Vec<u8>>,
+}
+
+impl PayloadV1 {
+    pub fn new(summary: Vec<u8>) -> Self {
+        PayloadV1 {
+            summary,
+            ..PayloadV1::default()
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        put_field(&mut bytes, SUMMARY, &self.summary);
+        if let Some(published_at) = self.published_at {
+            put_field(&mut bytes, PUBLISHED_AT, &u64_to_bytes(published_at));
+        }
+        if let Some(wait_time) = self.wait_time {
+            put_field(&mut bytes, WAIT_TIME, &u64_to_bytes(wait_time));
+        }
+        if let Some(ref software_version) = self.software_version {
+            put_field(&mut bytes, SOFTWARE_VERSION, software_version.as_bytes());
+        }
+        if let Some(ref signature) = self.signature {
+            put_field(&mut bytes, SIGNATURE, signature);
+        }
+        bytes
+    }
+
+    /// The part of `encoded`, this payload's encoding, that its signature
+    /// covers.
+    pub fn signed_bytes<'a>(&self, encoded: &'a [u8]) -> &'a [u8] {
+        match self.signature {
+            Some(ref signature) => &encoded[..encoded.len() - FIELD_HEADER_LEN - signature.len()],
+            None => encoded,
+        }
+    }
+
+    // Parse the fields that follow the magic and version.
+    fn parse(mut bytes: &[u8]) -> Result<Self, &'static str> {
+        let mut summary = None;
+        let mut payload = PayloadV1::default();
+
+        while !bytes.is_empty() {
+            if payload.signature.is_some() {
+                return Err("Signature is not the last field");
+            }
+            if bytes.len() < FIELD_HEADER_LEN {
+                return Err("Truncated field header");
+            }
+            let tag = bytes[0];
+            let len = (usize::from(bytes[1]) << 8) | usize::from(bytes[2]);
+            let value = bytes
+                .get(FIELD_HEADER_LEN..FIELD_HEADER_LEN + len)
+                .ok_or("Truncated field")?;
+            bytes = &bytes[FIELD_HEADER_LEN + len..];
+
+            match tag {
+                SUMMARY => summary = Some(value.to_vec()),
+                PUBLISHED_AT => payload.published_at = Some(u64_from_bytes(value)?),
+                WAIT_TIME => payload.wait_time = Some(u64_from_bytes(value)?),
+                SOFTWARE_VERSION => {
+                    let version = str::from_utf8(value).map_err(|_| "Invalid software version")?;
+                    payload.software_version = Some(version.into());
+                }
+                SIGNATURE => payload.signature = Some(value.to_vec()),
+                _ => (),
+            }
+        }
+
+        payload.summary = summary.ok_or("Missing summary")?;
+        Ok(payload)
+    }
+}
+
+fn put_field(bytes: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    assert!(value.len() <= 0xffff, "Payload field too long");
+    bytes.push(tag);
+    bytes.push((value.len() >> 8) as u8);
+    bytes.push(value.len() as u8);
+    bytes.extend_from_slice(value);
+}
+
+fn u64_to_bytes(value: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (value >> (56 - 8 * i)) as u8;
+    }
+    bytes
+}
+
+fn u64_from_bytes(bytes: &[u8]) -> Result<u64, &'static str> {
+    if bytes.len() != 8 {
+        return Err("Invalid integer field");
+    }
+    Ok(bytes
+        .iter()
+        .fold(0, |value, &byte| (value << 8) | u64::from(byte)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v1_round_trips_and_signature_covers_the_rest() {
+        let mut payload = PayloadV1::new(b"summary".to_vec());
+        payload.published_at = Some(1_500_000_000_000);
+        payload.wait_time = Some(250);
+        payload.software_version = Some("1.2.5".into());
+        let unsigned = payload.encode();
+
+        payload.signature = Some(b"signature".to_vec());
+        let encoded = payload.encode();
+
+        assert_eq!(
+            ConsensusPayload::parse(&encoded),
+            Ok(ConsensusPayload::V1(payload.clone()))
+        );
+        assert_eq!(payload.signed_bytes(&encoded), &unsigned[..]);
+    }
+
+    #[test]
+    fn unknown_fields_are_skipped() {
+        let mut encoded = PayloadV1::new(b"summary".to_vec()).encode();
+        put_field(&mut encoded, 0x42, b"from the future");
+
+        assert_eq!(
+            ConsensusPayload::parse(&encoded),
+            Ok(ConsensusPayload::V1(PayloadV1::new(b"summary".to_vec())))
+        );
+    }
+
+    #[test]
+    fn parses_legacy_and_rejects_garbage() {
+        assert_eq!(
+            ConsensusPayload::parse(b"Devmodesummary"),
+            Ok(ConsensusPayload::Legacy(b"summary".to_vec()))
+        );
+
+        let mut truncated = PayloadV1::new(b"summary".to_vec()).encode();
+        truncated.pop();
+        let mut future = MAGIC.to_vec();
+        future.push(VERSION + 1);
+
+        for invalid in &[&b"Bogus"[..], MAGIC, &truncated, &future] {
+            assert!(ConsensusPayload::parse(invalid).is_err());
+        }
+        let mut unsigned_after_signature = PayloadV1 {
+            signature: Some(vec![1]),
+            ..PayloadV1::new(vec![])
+        }
+        .encode();
+        put_field(&mut unsigned_after_signature, WAIT_TIME, &u64_to_bytes(0));
+        assert!(ConsensusPayload::parse(&unsigned_after_signature).is_err());
+    }
+}
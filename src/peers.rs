@@ -0,0 +1,174 @@
+/*
+ * Copyright 2018 Intel Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+
+use sawtooth_sdk::consensus::engine::{PeerId, PeerInfo};
+
+use hex;
+
+/// A node's public key, which is also its peer id, as written in hex on the
+/// command line and in settings.
+#[derive(Clone, PartialEq)]
+pub struct PeerKey(pub PeerId);
+
+impl FromStr for PeerKey {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        hex::decode(s)
+            .map(PeerKey)
+            .map_err(|_| "Invalid public key")
+    }
+}
+
+impl fmt::Display for PeerKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&hex::encode(&self.0))
+    }
+}
+
+impl fmt::Debug for PeerKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PeerKey({})", self)
+    }
+}
+
+/// What the engine knows about a connected peer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerStats {
+    pub connected_at: Instant,
+    /// When the peer last sent a consensus message, if it has.
+    pub last_message: Option<Instant>,
+    /// Number of blocks published by this engine that the peer reported
+    /// receiving.
+    pub blocks_received: u64,
+    /// Number of acks the peer sent for blocks it published.
+    pub acks_received: u64,
+}
+
+impl PeerStats {
+    fn new(now: Instant) -> Self {
+        PeerStats {
+            connected_at: now,
+            last_message: None,
+            blocks_received: 0,
+            acks_received: 0,
+        }
+    }
+}
+
+/// The peers currently connected to the validator.
+pub struct PeerTable {
+    peers: HashMap<PeerId, PeerStats>,
+}
+
+impl PeerTable {
+    /// Start with the peers the validator reported at startup.
+    pub fn new(peers: Vec<PeerInfo>, now: Instant) -> Self {
+        PeerTable {
+            peers: peers
+                .into_iter()
+                .map(|peer| (peer.peer_id, PeerStats::new(now)))
+                .collect(),
+        }
+    }
+
+    pub fn ids(&self) -> HashSet<PeerId> {
+        self.peers.keys().cloned().collect()
+    }
+
+    pub fn connected(&mut self, peer_id: PeerId, now: Instant) {
+        self.peers.insert(peer_id, PeerStats::new(now));
+    }
+
+    /// Forget a peer, returning what was known about it.
+    pub fn disconnected(&mut self, peer_id: &PeerId) -> Option<PeerStats> {
+        self.peers.remove(peer_id)
+    }
+
+    /// Record that the peer sent a message. Returns false if the peer isn't
+    /// known to be connected.
+    pub fn message_received(&mut self, peer_id: &PeerId, now: Instant) -> bool {
+        match self.peers.get_mut(peer_id) {
+            Some(stats) => {
+                stats.last_message = Some(now);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn block_received(&mut self, peer_id: &PeerId) {
+        if let Some(stats) = self.peers.get_mut(peer_id) {
+            stats.blocks_received += 1;
+        }
+    }
+
+    pub fn ack_received(&mut self, peer_id: &PeerId) {
+        if let Some(stats) = self.peers.get_mut(peer_id) {
+            stats.acks_received += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn peer_keys_are_hex() {
+        let key: PeerKey = "02a0ff".parse().unwrap();
+        assert_eq!(key.0, vec![0x02, 0xa0, 0xff]);
+        assert_eq!(key.to_string(), "02a0ff");
+
+        for invalid in &["", "2a0ff", "02a0fg", "02a0é"] {
+            assert!(invalid.parse::<PeerKey>().is_err());
+        }
+    }
+
+    #[test]
+    fn tracks_membership_and_activity() {
+        let start = Instant::now();
+        let mut table = PeerTable::new(
+            vec![PeerInfo {
+                peer_id: b"a".to_vec(),
+            }],
+            start,
+        );
+        let later = start + Duration::from_secs(1);
+        table.connected(b"b".to_vec(), later);
+
+        assert!(table.message_received(&b"a".to_vec(), later));
+        assert!(!table.message_received(&b"c".to_vec(), later));
+        table.block_received(&b"a".to_vec());
+        table.ack_received(&b"a".to_vec());
+        table.ack_received(&b"a".to_vec());
+
+        let a = table.disconnected(&b"a".to_vec()).unwrap();
+        assert_eq!(a.connected_at, start);
+        assert_eq!(a.last_message, Some(later));
+        assert_eq!((a.blocks_received, a.acks_received), (1, 2));
+        assert_eq!(table.ids(), vec![b"b".to_vec()].into_iter().collect());
+        assert_eq!(table.disconnected(&b"a".to_vec()), None);
+    }
+}
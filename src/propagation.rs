@@ -0,0 +1,273 @@
+/*
+ * Copyright 2018 Intel Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use sawtooth_sdk::consensus::engine::{BlockId, PeerId};
+
+use hex;
+
+/// How a block published by this engine reached its peers, going by the
+/// "received" messages they sent back.
+#[derive(Debug, PartialEq)]
+pub struct PropagationSummary {
+    pub block_id: BlockId,
+    /// Number of peers connected when the block was published.
+    pub expected: usize,
+    /// Each peer that reported receiving the block, and how long after
+    /// publishing it did, in the order they reported.
+    pub latencies: Vec<(PeerId, Duration)>,
+    /// True if some peers hadn't reported by the time the timeout elapsed.
+    pub timed_out: bool,
+}
+
+impl PropagationSummary {
+    /// The fraction of the expected peers that reported receiving the block.
+    pub fn coverage(&self) -> f64 {
+        if self.expected == 0 {
+            1.0
+        } else {
+            self.latencies.len() as f64 / self.expected as f64
+        }
+    }
+
+    pub fn max_latency(&self) -> Option<Duration> {
+        self.latencies.iter().map(|&(_, latency)| latency).max()
+    }
+
+    pub fn mean_latency(&self) -> Option<Duration> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        let total = self
+            .latencies
+            .iter()
+            .fold(Duration::from_secs(0), |total, &(_, latency)| {
+                total + latency
+            });
+        Some(total / self.latencies.len() as u32)
+    }
+}
+
+impl fmt::Display for PropagationSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Block {} reached {}/{} peers ({:.0}%)",
+            hex::encode(&self.block_id),
+            self.latencies.len(),
+            self.expected,
+            self.coverage() * 100.0
+        )?;
+        if let (Some(mean), Some(max)) = (self.mean_latency(), self.max_latency()) {
+            write!(f, " -- mean latency {:?}, max {:?}", mean, max)?;
+        }
+        if self.timed_out {
+            f.write_str(" -- timed out")?;
+        }
+        Ok(())
+    }
+}
+
+struct Pending {
+    published_at: Instant,
+    expected: usize,
+    waiting_on: HashSet<PeerId>,
+    latencies: Vec<(PeerId, Duration)>,
+}
+
+impl Pending {
+    fn summary(self, block_id: BlockId, timed_out: bool) -> PropagationSummary {
+        PropagationSummary {
+            block_id,
+            expected: self.expected,
+            latencies: self.latencies,
+            timed_out,
+        }
+    }
+}
+
+/// Keeps track of which peers have reported receiving the blocks this engine
+/// published, until all of them have or the timeout elapses.
+pub struct PropagationTracker {
+    timeout: Duration,
+    pending: HashMap<BlockId, Pending>,
+}
+
+impl PropagationTracker {
+    pub fn new(timeout: Duration) -> Self {
+        PropagationTracker {
+            timeout,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Start tracking a block that was just published to `peers`.
+    pub fn published(&mut self, block_id: BlockId, peers: HashSet<PeerId>, now: Instant) {
+        if peers.is_empty() {
+            debug!(
+                "No peers to track propagation of {}",
+                hex::encode(&block_id)
+            );
+            return;
+        }
+
+        self.pending.insert(
+            block_id,
+            Pending {
+                published_at: now,
+                expected: peers.len(),
+                waiting_on: peers,
+                latencies: vec![],
+            },
+        );
+    }
+
+    /// Record that `peer` received `block_id`. Returns the summary if that was
+    /// the last peer being waited on.
+    pub fn received(
+        &mut self,
+        block_id: &BlockId,
+        peer: &PeerId,
+        now: Instant,
+    ) -> Option<PropagationSummary> {
+        let complete = {
+            let pending = self.pending.get_mut(block_id)?;
+            if !pending.waiting_on.remove(peer) {
+                return None;
+            }
+            let latency = now.duration_since(pending.published_at);
+            pending.latencies.push((peer.clone(), latency));
+            pending.waiting_on.is_empty()
+        };
+
+        if complete {
+            self.pending
+                .remove(block_id)
+                .map(|pending| pending.summary(block_id.clone(), false))
+        } else {
+            None
+        }
+    }
+
+    /// Stop waiting on a peer that left. Returns the summaries of blocks that
+    /// were only waiting on that peer.
+    pub fn peer_disconnected(&mut self, peer: &PeerId) -> Vec<PropagationSummary> {
+        for pending in self.pending.values_mut() {
+            pending.waiting_on.remove(peer);
+        }
+        self.take(|pending| pending.waiting_on.is_empty(), false)
+    }
+
+    /// Returns the summaries of blocks that have been waiting for longer than
+    /// the timeout, and stops tracking them.
+    pub fn expire(&mut self, now: Instant) -> Vec<PropagationSummary> {
+        let timeout = self.timeout;
+        self.take(
+            |pending| now.duration_since(pending.published_at) >= timeout,
+            true,
+        )
+    }
+
+    fn take<F>(&mut self, done: F, timed_out: bool) -> Vec<PropagationSummary>
+    where
+        F: Fn(&Pending) -> bool,
+    {
+        let block_ids: Vec<BlockId> = self
+            .pending
+            .iter()
+            .filter(|&(_, pending)| done(pending))
+            .map(|(block_id, _)| block_id.clone())
+            .collect();
+
+        block_ids
+            .into_iter()
+            .filter_map(|block_id| {
+                self.pending
+                    .remove(&block_id)
+                    .map(|pending| pending.summary(block_id, timed_out))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers(ids: &[&str]) -> HashSet<PeerId> {
+        ids.iter().map(|id| id.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn summary_once_every_peer_received() {
+        let start = Instant::now();
+        let mut tracker = PropagationTracker::new(Duration::from_secs(10));
+        tracker.published(b"block".to_vec(), peers(&["a", "b"]), start);
+
+        let later = start + Duration::from_millis(100);
+        assert_eq!(
+            tracker.received(&b"block".to_vec(), &b"a".to_vec(), later),
+            None
+        );
+        // Repeats and unknown blocks are ignored.
+        assert_eq!(
+            tracker.received(&b"block".to_vec(), &b"a".to_vec(), later),
+            None
+        );
+        assert_eq!(
+            tracker.received(&b"other".to_vec(), &b"b".to_vec(), later),
+            None
+        );
+
+        let summary = tracker
+            .received(
+                &b"block".to_vec(),
+                &b"b".to_vec(),
+                start + Duration::from_millis(300),
+            )
+            .unwrap();
+        assert_eq!(summary.coverage(), 1.0);
+        assert_eq!(summary.mean_latency(), Some(Duration::from_millis(200)));
+        assert_eq!(summary.max_latency(), Some(Duration::from_millis(300)));
+        assert!(!summary.timed_out);
+        assert!(tracker.expire(start + Duration::from_secs(60)).is_empty());
+    }
+
+    #[test]
+    fn summary_on_timeout_or_when_remaining_peers_leave() {
+        let start = Instant::now();
+        let mut tracker = PropagationTracker::new(Duration::from_secs(10));
+        tracker.published(b"first".to_vec(), peers(&["a", "b"]), start);
+        tracker.published(b"second".to_vec(), peers(&["a", "b", "c"]), start);
+        tracker.received(&b"first".to_vec(), &b"a".to_vec(), start);
+
+        let left = tracker.peer_disconnected(&b"b".to_vec());
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].block_id, b"first".to_vec());
+        assert_eq!(left[0].coverage(), 0.5);
+
+        assert!(tracker.expire(start + Duration::from_secs(5)).is_empty());
+        let expired = tracker.expire(start + Duration::from_secs(10));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].block_id, b"second".to_vec());
+        assert_eq!(expired[0].coverage(), 0.0);
+        assert!(expired[0].timed_out);
+    }
+}
@@ -0,0 +1,176 @@
+/*
+ * Copyright 2018 Intel Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use sawtooth_sdk::consensus::engine::PeerId;
+
+use hex;
+
+/// Ways a peer can misbehave that count against it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Misbehaviour {
+    /// Published a block whose consensus data didn't check out.
+    FailedConsensusCheck,
+    /// Published a block the validator found invalid.
+    InvalidBlock,
+    /// Sent a consensus message of a type devmode doesn't know.
+    UnknownMessage,
+}
+
+impl Misbehaviour {
+    fn penalty(self) -> u64 {
+        match self {
+            Misbehaviour::FailedConsensusCheck => 1,
+            Misbehaviour::InvalidBlock => 2,
+            Misbehaviour::UnknownMessage => 1,
+        }
+    }
+}
+
+impl fmt::Display for Misbehaviour {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Misbehaviour::FailedConsensusCheck => "failed consensus check",
+            Misbehaviour::InvalidBlock => "invalid block",
+            Misbehaviour::UnknownMessage => "unknown message",
+        })
+    }
+}
+
+/// What to do with new blocks from a peer whose misbehaviour score is over
+/// the threshold.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MisbehaviourAction {
+    #[default]
+    Ignore,
+    Fail,
+}
+
+impl MisbehaviourAction {
+    pub const NAMES: &'static [&'static str] = &["ignore", "fail"];
+}
+
+impl FromStr for MisbehaviourAction {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(MisbehaviourAction::Ignore),
+            "fail" => Ok(MisbehaviourAction::Fail),
+            _ => Err("Invalid misbehaviour action"),
+        }
+    }
+}
+
+impl fmt::Display for MisbehaviourAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            MisbehaviourAction::Ignore => "ignore",
+            MisbehaviourAction::Fail => "fail",
+        })
+    }
+}
+
+/// Misbehaviour scores of the peers this node has heard from. Scores only go
+/// up; once a peer's score is over the threshold, its blocks get the
+/// configured action without being checked.
+pub struct Reputation {
+    threshold: Option<u64>,
+    action: MisbehaviourAction,
+    scores: HashMap<PeerId, u64>,
+}
+
+impl Reputation {
+    /// Without a threshold, scores are kept but no peer is ever sanctioned.
+    pub fn new(threshold: Option<u64>, action: MisbehaviourAction) -> Self {
+        Reputation {
+            threshold,
+            action,
+            scores: HashMap::new(),
+        }
+    }
+
+    pub fn score(&self, peer_id: &PeerId) -> u64 {
+        self.scores.get(peer_id).cloned().unwrap_or(0)
+    }
+
+    pub fn threshold(&self) -> Option<u64> {
+        self.threshold
+    }
+
+    pub fn penalize(&mut self, peer_id: &PeerId, misbehaviour: Misbehaviour) {
+        let score = {
+            let score = self.scores.entry(peer_id.clone()).or_insert(0);
+            *score += misbehaviour.penalty();
+            *score
+        };
+        debug!(
+            "Misbehaviour score of {} is now {} after {}",
+            hex::encode(peer_id),
+            score,
+            misbehaviour
+        );
+
+        if let Some(threshold) = self.threshold {
+            if score > threshold && score - misbehaviour.penalty() <= threshold {
+                warn!(
+                    "Peer {} is over the misbehaviour threshold ({} > {}); will {} its blocks",
+                    hex::encode(peer_id),
+                    score,
+                    threshold,
+                    self.action
+                );
+            }
+        }
+    }
+
+    /// The action to take on the peer's blocks, if it's over the threshold.
+    pub fn sanction(&self, peer_id: &PeerId) -> Option<MisbehaviourAction> {
+        match self.threshold {
+            Some(threshold) if self.score(peer_id) > threshold => Some(self.action),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_are_sanctioned_once_over_the_threshold() {
+        let mut reputation = Reputation::new(Some(2), MisbehaviourAction::Fail);
+        let peer = b"a".to_vec();
+
+        reputation.penalize(&peer, Misbehaviour::UnknownMessage);
+        reputation.penalize(&peer, Misbehaviour::FailedConsensusCheck);
+        assert_eq!(reputation.score(&peer), 2);
+        assert_eq!(reputation.sanction(&peer), None);
+
+        reputation.penalize(&peer, Misbehaviour::InvalidBlock);
+        assert_eq!(reputation.score(&peer), 4);
+        assert_eq!(reputation.sanction(&peer), Some(MisbehaviourAction::Fail));
+        assert_eq!(reputation.sanction(&b"b".to_vec()), None);
+
+        let mut unlimited = Reputation::new(None, MisbehaviourAction::Ignore);
+        unlimited.penalize(&peer, Misbehaviour::InvalidBlock);
+        assert_eq!(unlimited.sanction(&peer), None);
+    }
+}
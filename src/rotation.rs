@@ -0,0 +1,79 @@
+/*
+ * Copyright 2018 Intel Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+use std::collections::HashSet;
+
+use sawtooth_sdk::consensus::engine::PeerId;
+
+/// Takes turns publishing among the nodes of the network. The nodes are
+/// ordered by id, and the leader for each height is the next one in that
+/// order, so every node that knows the same peers agrees on the leader.
+pub struct Rotation {
+    members: Vec<PeerId>,
+}
+
+impl Rotation {
+    pub fn new(local_id: &PeerId, mut peers: HashSet<PeerId>) -> Self {
+        peers.insert(local_id.clone());
+        let mut members: Vec<PeerId> = peers.into_iter().collect();
+        members.sort();
+        Rotation { members }
+    }
+
+    /// The node that should publish the block at `height`.
+    pub fn leader(&self, height: u64) -> &PeerId {
+        &self.members[(height % self.members.len() as u64) as usize]
+    }
+
+    /// The node's turn at `height`: 0 for the leader, 1 for the node that
+    /// takes over if the leader is silent, and so on. None if the node isn't
+    /// a member.
+    pub fn rank(&self, peer_id: &PeerId, height: u64) -> Option<u64> {
+        let count = self.members.len() as u64;
+        let position = self.members.iter().position(|id| id == peer_id)? as u64;
+        Some((position + count - height % count) % count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leader_rotates_through_sorted_members() {
+        let peers = vec![b"c".to_vec(), b"a".to_vec()].into_iter().collect();
+        let rotation = Rotation::new(&b"b".to_vec(), peers);
+
+        assert_eq!(rotation.leader(0), &b"a".to_vec());
+        assert_eq!(rotation.leader(1), &b"b".to_vec());
+        assert_eq!(rotation.leader(5), &b"c".to_vec());
+
+        assert_eq!(rotation.rank(&b"b".to_vec(), 1), Some(0));
+        assert_eq!(rotation.rank(&b"c".to_vec(), 1), Some(1));
+        assert_eq!(rotation.rank(&b"a".to_vec(), 1), Some(2));
+        assert_eq!(rotation.rank(&b"d".to_vec(), 1), None);
+    }
+
+    #[test]
+    fn single_node_always_leads() {
+        let rotation = Rotation::new(&b"a".to_vec(), HashSet::new());
+
+        for height in 0..3 {
+            assert_eq!(rotation.rank(&b"a".to_vec(), height), Some(0));
+        }
+    }
+}
@@ -0,0 +1,221 @@
+/*
+ * Copyright 2018 Intel Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use rand;
+use rand::distributions::{Exp, IndependentSample};
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use settings::DevmodeSettings;
+
+// In milliseconds
+const DEFAULT_WAIT_TIME: u64 = 0;
+
+/// How the time to wait before publishing a block is chosen from the
+/// minimum and maximum wait time settings.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ScheduleMode {
+    /// Uniformly random between the minimum and maximum, inclusive.
+    #[default]
+    Uniform,
+    /// Always the minimum wait time.
+    Fixed,
+    /// The minimum plus an exponentially distributed delay whose mean is the
+    /// difference between the maximum and minimum, like Poisson arrivals.
+    Exponential,
+}
+
+impl ScheduleMode {
+    pub const NAMES: &'static [&'static str] = &["uniform", "fixed", "exponential"];
+}
+
+impl FromStr for ScheduleMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(ScheduleMode::Uniform),
+            "fixed" => Ok(ScheduleMode::Fixed),
+            "exponential" => Ok(ScheduleMode::Exponential),
+            _ => Err("Invalid publishing schedule"),
+        }
+    }
+}
+
+impl fmt::Display for ScheduleMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            ScheduleMode::Uniform => "uniform",
+            ScheduleMode::Fixed => "fixed",
+            ScheduleMode::Exponential => "exponential",
+        })
+    }
+}
+
+/// Chooses the time to wait before publishing each block. With a seed, the
+/// same sequence of wait times is produced on every run.
+pub struct PublishingSchedule {
+    mode: ScheduleMode,
+    seed: Option<u64>,
+    rng: XorShiftRng,
+}
+
+impl PublishingSchedule {
+    pub fn new(mode: ScheduleMode, seed: Option<u64>) -> Self {
+        let rng = match seed {
+            Some(seed) => XorShiftRng::from_seed(seed_words(seed)),
+            None => rand::weak_rng(),
+        };
+
+        PublishingSchedule { mode, seed, rng }
+    }
+
+    pub fn mode(&self) -> ScheduleMode {
+        self.mode
+    }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    // Calculate the time to wait between publishing blocks, within
+    // `wait_time_range`.
+    pub fn wait_time(&mut self, settings: &DevmodeSettings) -> Duration {
+        let (min_wait_time, max_wait_time) = wait_time_range(settings);
+
+        debug!("Min: {:?} -- Max: {:?}", min_wait_time, max_wait_time);
+
+        let wait_time = if min_wait_time == max_wait_time {
+            min_wait_time
+        } else {
+            match self.mode {
+                ScheduleMode::Uniform => self
+                    .rng
+                    .gen_range(min_wait_time, max_wait_time.saturating_add(1)),
+                ScheduleMode::Fixed => min_wait_time,
+                ScheduleMode::Exponential => {
+                    let mean = (max_wait_time - min_wait_time) as f64;
+                    let delay = Exp::new(1.0 / mean).ind_sample(&mut self.rng);
+                    min_wait_time.saturating_add(delay as u64)
+                }
+            }
+        };
+
+        info!("Wait time: {:?}ms", wait_time);
+
+        Duration::from_millis(wait_time)
+    }
+}
+
+/// The shortest and longest wait times the settings allow, in milliseconds.
+/// If the minimum wait time is greater than the maximum, the only wait time
+/// allowed is DEFAULT_WAIT_TIME.
+pub fn wait_time_range(settings: &DevmodeSettings) -> (u64, u64) {
+    let min_wait_time = duration_to_millis(settings.min_wait_time);
+    let max_wait_time = duration_to_millis(settings.max_wait_time);
+    if min_wait_time > max_wait_time {
+        (DEFAULT_WAIT_TIME, DEFAULT_WAIT_TIME)
+    } else {
+        (min_wait_time, max_wait_time)
+    }
+}
+
+// XorShiftRng can't be seeded with all zeros, so mix the seed with a constant
+fn seed_words(seed: u64) -> [u32; 4] {
+    let low = seed as u32;
+    let high = (seed >> 32) as u32;
+    [low, high, low ^ 0x9e37_79b9, high ^ 0x7f4a_7c15]
+}
+
+// Saturates rather than overflowing on durations too long to matter.
+pub fn duration_to_millis(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .saturating_mul(1000)
+        .saturating_add(u64::from(duration.subsec_millis()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(min_ms: u64, max_ms: u64) -> DevmodeSettings {
+        DevmodeSettings {
+            min_wait_time: Duration::from_millis(min_ms),
+            max_wait_time: Duration::from_millis(max_ms),
+            ..DevmodeSettings::default()
+        }
+    }
+
+    #[test]
+    fn range_is_inclusive_and_inverted_range_uses_default() {
+        let mut schedule = PublishingSchedule::new(ScheduleMode::Uniform, None);
+
+        assert_eq!(
+            schedule.wait_time(&settings(5, 5)),
+            Duration::from_millis(5)
+        );
+        assert_eq!(
+            schedule.wait_time(&settings(2000, 1000)),
+            Duration::from_millis(DEFAULT_WAIT_TIME)
+        );
+    }
+
+    #[test]
+    fn seeded_schedules_repeat() {
+        for mode in &[ScheduleMode::Uniform, ScheduleMode::Exponential] {
+            let mut first = PublishingSchedule::new(*mode, Some(7));
+            let mut second = PublishingSchedule::new(*mode, Some(7));
+
+            for _ in 0..10 {
+                let wait_time = first.wait_time(&settings(100, 900));
+                assert_eq!(wait_time, second.wait_time(&settings(100, 900)));
+                assert!(wait_time >= Duration::from_millis(100));
+            }
+        }
+    }
+
+    #[test]
+    fn fixed_uses_minimum() {
+        let mut schedule = PublishingSchedule::new(ScheduleMode::Fixed, None);
+
+        assert_eq!(
+            schedule.wait_time(&settings(100, 900)),
+            Duration::from_millis(100)
+        );
+    }
+
+    #[test]
+    fn huge_wait_times_saturate() {
+        let huge = Duration::from_secs(u64::MAX);
+        assert_eq!(duration_to_millis(huge), u64::MAX);
+
+        let mut schedule = PublishingSchedule::new(ScheduleMode::Fixed, None);
+        let huge_settings = DevmodeSettings {
+            min_wait_time: huge,
+            max_wait_time: huge,
+            ..DevmodeSettings::default()
+        };
+        assert_eq!(
+            schedule.wait_time(&huge_settings),
+            Duration::from_millis(u64::MAX)
+        );
+    }
+}
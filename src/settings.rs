@@ -0,0 +1,192 @@
+/*
+ * Copyright 2018 Intel Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+use fork::ForkResolution;
+use peers::PeerKey;
+use schedule::ScheduleMode;
+
+pub const MIN_WAIT_TIME: &str = "sawtooth.consensus.min_wait_time";
+pub const MAX_WAIT_TIME: &str = "sawtooth.consensus.max_wait_time";
+pub const MIN_WAIT_TIME_MS: &str = "sawtooth.consensus.min_wait_time_ms";
+pub const MAX_WAIT_TIME_MS: &str = "sawtooth.consensus.max_wait_time_ms";
+pub const FORK_RESOLUTION: &str = "sawtooth.consensus.devmode.fork_resolution";
+pub const PUBLISHING_SCHEDULE: &str = "sawtooth.consensus.devmode.publishing_schedule";
+pub const PUBLISHING_SEED: &str = "sawtooth.consensus.devmode.publishing_seed";
+pub const PUBLISHER: &str = "sawtooth.consensus.devmode.publisher";
+
+/// The on-chain settings devmode reads, as of a particular chain head.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DevmodeSettings {
+    /// sawtooth.consensus.min_wait_time_ms, or else
+    /// sawtooth.consensus.min_wait_time
+    pub min_wait_time: Duration,
+    /// sawtooth.consensus.max_wait_time_ms, or else
+    /// sawtooth.consensus.max_wait_time
+    pub max_wait_time: Duration,
+    /// sawtooth.consensus.devmode.fork_resolution
+    pub fork_resolution: Option<ForkResolution>,
+    /// sawtooth.consensus.devmode.publishing_schedule
+    pub publishing_schedule: Option<ScheduleMode>,
+    /// sawtooth.consensus.devmode.publishing_seed
+    pub publishing_seed: Option<u64>,
+    /// sawtooth.consensus.devmode.publisher
+    pub publisher: Option<PeerKey>,
+}
+
+impl DevmodeSettings {
+    /// The setting keys to request from the validator.
+    pub fn keys() -> Vec<String> {
+        vec![
+            String::from(MIN_WAIT_TIME),
+            String::from(MAX_WAIT_TIME),
+            String::from(MIN_WAIT_TIME_MS),
+            String::from(MAX_WAIT_TIME_MS),
+            String::from(FORK_RESOLUTION),
+            String::from(PUBLISHING_SCHEDULE),
+            String::from(PUBLISHING_SEED),
+            String::from(PUBLISHER),
+        ]
+    }
+
+    /// Build the settings from the values returned by the validator. Values
+    /// that are missing or can't be parsed are left at their defaults, with a
+    /// warning for the ones that can't be parsed.
+    pub fn from_values(values: &HashMap<String, String>) -> Self {
+        let min_wait_time = wait_time(values, MIN_WAIT_TIME_MS, MIN_WAIT_TIME).unwrap_or_default();
+        let max_wait_time = wait_time(values, MAX_WAIT_TIME_MS, MAX_WAIT_TIME).unwrap_or_default();
+        if min_wait_time > max_wait_time {
+            warn!(
+                "Minimum wait time {:?} is greater than maximum wait time {:?}",
+                min_wait_time, max_wait_time
+            );
+        }
+
+        DevmodeSettings {
+            min_wait_time,
+            max_wait_time,
+            fork_resolution: parse(values, FORK_RESOLUTION),
+            publishing_schedule: parse(values, PUBLISHING_SCHEDULE),
+            publishing_seed: parse(values, PUBLISHING_SEED),
+            publisher: parse(values, PUBLISHER),
+        }
+    }
+
+    /// Log every setting that differs from `previous`.
+    pub fn log_changes(&self, previous: &DevmodeSettings, at: &str) {
+        if self.min_wait_time != previous.min_wait_time {
+            info!(
+                "min_wait_time changed {:?} -> {:?} at {}",
+                previous.min_wait_time, self.min_wait_time, at
+            );
+        }
+        if self.max_wait_time != previous.max_wait_time {
+            info!(
+                "max_wait_time changed {:?} -> {:?} at {}",
+                previous.max_wait_time, self.max_wait_time, at
+            );
+        }
+        if self.fork_resolution != previous.fork_resolution {
+            info!(
+                "fork_resolution changed {:?} -> {:?} at {}",
+                previous.fork_resolution, self.fork_resolution, at
+            );
+        }
+        if self.publishing_schedule != previous.publishing_schedule {
+            info!(
+                "publishing_schedule changed {:?} -> {:?} at {}",
+                previous.publishing_schedule, self.publishing_schedule, at
+            );
+        }
+        if self.publishing_seed != previous.publishing_seed {
+            info!(
+                "publishing_seed changed {:?} -> {:?} at {}",
+                previous.publishing_seed, self.publishing_seed, at
+            );
+        }
+        if self.publisher != previous.publisher {
+            info!(
+                "publisher changed {:?} -> {:?} at {}",
+                previous.publisher, self.publisher, at
+            );
+        }
+    }
+}
+
+// Parse the setting `key`. Unset settings are skipped silently, invalid ones
+// with a warning.
+fn parse<T: FromStr>(values: &HashMap<String, String>, key: &str) -> Option<T> {
+    let value = values.get(key).filter(|value| !value.is_empty())?;
+    value
+        .parse()
+        .map_err(|_| warn!("Invalid value for {}: {:?}", key, value))
+        .ok()
+}
+
+// Read a wait time from the millisecond setting `ms_key`, falling back to
+// the second setting `secs_key`.
+fn wait_time(values: &HashMap<String, String>, ms_key: &str, secs_key: &str) -> Option<Duration> {
+    parse(values, ms_key)
+        .map(Duration::from_millis)
+        .or_else(|| parse(values, secs_key).map(Duration::from_secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_values_and_defaults_the_rest() {
+        let mut values = HashMap::new();
+        values.insert(String::from(MIN_WAIT_TIME), String::from("5"));
+        values.insert(String::from(MAX_WAIT_TIME), String::from("soon"));
+        values.insert(String::from(FORK_RESOLUTION), String::from("first-seen"));
+        values.insert(String::from(PUBLISHER), String::from("0a0b"));
+
+        assert_eq!(
+            DevmodeSettings::from_values(&values),
+            DevmodeSettings {
+                min_wait_time: Duration::from_secs(5),
+                max_wait_time: Duration::from_secs(0),
+                fork_resolution: Some(ForkResolution::FirstSeen),
+                publisher: Some(PeerKey(vec![0x0a, 0x0b])),
+                ..DevmodeSettings::default()
+            }
+        );
+        assert_eq!(
+            DevmodeSettings::from_values(&HashMap::new()),
+            DevmodeSettings::default()
+        );
+    }
+
+    #[test]
+    fn millisecond_settings_take_precedence() {
+        let mut values = HashMap::new();
+        values.insert(String::from(MIN_WAIT_TIME), String::from("1"));
+        values.insert(String::from(MAX_WAIT_TIME), String::from("2"));
+        values.insert(String::from(MIN_WAIT_TIME_MS), String::from("250"));
+        values.insert(String::from(MAX_WAIT_TIME_MS), String::from("later"));
+
+        let settings = DevmodeSettings::from_values(&values);
+
+        assert_eq!(settings.min_wait_time, Duration::from_millis(250));
+        assert_eq!(settings.max_wait_time, Duration::from_secs(2));
+    }
+}
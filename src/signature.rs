@@ -0,0 +1,141 @@
+/*
+ * Copyright 2018 Intel Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+use std::fs;
+use std::path::Path;
+
+use sawtooth_sdk::signing::secp256k1::{Secp256k1PrivateKey, Secp256k1PublicKey};
+use sawtooth_sdk::signing::{self, Context};
+
+use hex;
+
+/// Where Sawtooth keeps the validator's private key by default.
+pub const DEFAULT_KEY_FILE: &str = "/etc/sawtooth/keys/validator.priv";
+
+const PRIVATE_KEY_LEN: usize = 32;
+
+/// Signs consensus payloads with the local validator's key, and checks the
+/// signatures on other validators' payloads.
+pub struct PayloadSigner {
+    context: Box<dyn Context>,
+    private_key: Secp256k1PrivateKey,
+    public_key: Vec<u8>,
+}
+
+impl PayloadSigner {
+    /// Load the private key from a Sawtooth key file, which holds the key as
+    /// a hex string.
+    pub fn from_key_file(path: &Path) -> Result<Self, String> {
+        let key = fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read key file {}: {}", path.display(), err))?;
+        Self::from_hex(key.trim())
+            .map_err(|err| format!("Invalid key in {}: {}", path.display(), err))
+    }
+
+    /// Load a private key given as a hex string. Deriving the public key
+    /// checks that the key is usable, so a bad key fails here rather than on
+    /// every signature.
+    pub fn from_hex(key: &str) -> Result<Self, String> {
+        let len = hex::decode(key)?.len();
+        if len != PRIVATE_KEY_LEN {
+            return Err(format!(
+                "Expected a {}-byte private key, got {} bytes",
+                PRIVATE_KEY_LEN, len
+            ));
+        }
+
+        let private_key = Secp256k1PrivateKey::from_hex(key).map_err(|err| err.to_string())?;
+        let context = signing::create_context("secp256k1")
+            .map_err(|err| format!("Failed to create signing context: {}", err))?;
+        let public_key = context
+            .get_public_key(&private_key)
+            .map(|public_key| public_key.as_slice().to_vec())
+            .map_err(|err| format!("Invalid private key: {}", err))?;
+
+        Ok(PayloadSigner {
+            context,
+            private_key,
+            public_key,
+        })
+    }
+
+    /// The public key matching the private key, which is the signer id of
+    /// the blocks this node publishes.
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, String> {
+        let signature = self
+            .context
+            .sign(message, &self.private_key)
+            .map_err(|err| format!("Failed to sign consensus payload: {}", err))?;
+        hex::decode(&signature).map_err(String::from)
+    }
+
+    /// Returns true if `signature` is a valid signature over `message` by the
+    /// holder of `public_key`.
+    pub fn verify(&self, signature: &[u8], message: &[u8], public_key: &[u8]) -> bool {
+        let public_key = match Secp256k1PublicKey::from_hex(&hex::encode(public_key)) {
+            Ok(public_key) => public_key,
+            Err(_) => return false,
+        };
+
+        self.context
+            .verify(&hex::encode(signature), message, &public_key)
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::{OTHER_KEY, TEST_KEY};
+
+    #[test]
+    fn signatures_verify_only_for_the_signer_and_message() {
+        let signer = PayloadSigner::from_hex(TEST_KEY).unwrap();
+        let other = PayloadSigner::from_hex(OTHER_KEY).unwrap();
+        let public_key = signer.public_key();
+
+        let signature = signer.sign(b"summary").unwrap();
+        assert!(signer.verify(&signature, b"summary", public_key));
+        assert!(other.verify(&signature, b"summary", public_key));
+
+        assert!(!signer.verify(&signature, b"tampered", public_key));
+        assert!(!signer.verify(&signature, b"summary", other.public_key()));
+
+        let other_signature = other.sign(b"summary").unwrap();
+        assert!(!signer.verify(&other_signature, b"summary", public_key));
+
+        let mut forged = signature.clone();
+        let last = forged.len() - 1;
+        forged[last] ^= 1;
+        assert!(!signer.verify(&forged, b"summary", public_key));
+        assert!(!signer.verify(&[], b"summary", public_key));
+        assert!(!signer.verify(&signature, b"summary", b"not a key"));
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        let zero = "00".repeat(32);
+        let too_long = format!("{}00", TEST_KEY);
+        for invalid in &["", "2f1e", "not hex", &zero, &too_long] {
+            assert!(PayloadSigner::from_hex(invalid).is_err());
+        }
+    }
+}
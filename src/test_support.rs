@@ -0,0 +1,351 @@
+/*
+ * Copyright 2018 Intel Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+//! In-memory stand-ins for the validator, used to drive `DevmodeEngine::start`
+//! from `cargo test` without a running network.
+
+#![allow(dead_code)]
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use sawtooth_sdk::consensus::{engine::*, service::Service};
+
+use clock::Clock;
+
+/// Seconds after the Unix epoch at which `ManualClock`'s wall clock starts.
+pub const WALL_CLOCK_START: u64 = 1_500_000_000;
+
+/// Fixed secp256k1 private keys, as hex, for tests of signed payloads.
+pub const TEST_KEY: &str = "2f1e7b7a130d7ba9da0068b3bb0ba1d79e7e77110302c9f746c3c2a63fe40088";
+pub const OTHER_KEY: &str = "7f664d71e4200b4a2989558d1f6006d0dac9771a36a546b1a47c384ec9c4f04b";
+
+/// A virtual clock that only moves when told to.
+///
+/// Every call to `now` advances the clock by `tick`, which lets a test make
+/// the engine's timers expire without real waits.
+pub struct ManualClock {
+    origin: Instant,
+    elapsed: Mutex<Duration>,
+    tick: Duration,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock::with_tick(Duration::from_secs(0))
+    }
+
+    pub fn with_tick(tick: Duration) -> Self {
+        ManualClock {
+            origin: Instant::now(),
+            elapsed: Mutex::new(Duration::from_secs(0)),
+            tick,
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+
+    /// Total virtual time that has passed since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        let mut elapsed = self.elapsed.lock().unwrap();
+        *elapsed += self.tick;
+        self.origin + *elapsed
+    }
+
+    fn system_time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(WALL_CLOCK_START) + self.elapsed()
+    }
+}
+
+/// A single call made by the engine against the `Service`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ServiceCall {
+    SendTo(PeerId, String, Vec<u8>),
+    Broadcast(String, Vec<u8>),
+    InitializeBlock(Option<BlockId>),
+    SummarizeBlock,
+    FinalizeBlock(Vec<u8>),
+    CancelBlock,
+    CheckBlocks(Vec<BlockId>),
+    CommitBlock(BlockId),
+    IgnoreBlock(BlockId),
+    FailBlock(BlockId),
+    GetBlocks(Vec<BlockId>),
+    GetChainHead,
+    GetSettings(BlockId, Vec<String>),
+    GetState(BlockId, Vec<String>),
+}
+
+#[derive(Default)]
+struct MockState {
+    calls: Vec<ServiceCall>,
+    blocks: HashMap<BlockId, Block>,
+    chain_head: Option<BlockId>,
+    settings: HashMap<String, String>,
+    summarize_results: VecDeque<Result<Vec<u8>, Error>>,
+    finalize_results: VecDeque<Result<BlockId, Error>>,
+    settings_errors: VecDeque<Error>,
+    published: u64,
+}
+
+/// A scriptable `Service` that records every call made against it.
+///
+/// Clones share the same underlying state, so a test can hand one clone to
+/// the engine and keep another to script responses and inspect the calls.
+#[derive(Clone, Default)]
+pub struct MockService {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockService {
+    pub fn new() -> Self {
+        MockService::default()
+    }
+
+    /// Make `block` available to `get_blocks`.
+    pub fn add_block(&self, block: Block) {
+        let mut state = self.state.lock().unwrap();
+        state.blocks.insert(block.block_id.clone(), block);
+    }
+
+    /// Make `block` available to `get_blocks` and report it from
+    /// `get_chain_head`.
+    pub fn set_chain_head(&self, block: Block) {
+        let mut state = self.state.lock().unwrap();
+        state.chain_head = Some(block.block_id.clone());
+        state.blocks.insert(block.block_id.clone(), block);
+    }
+
+    /// Set the on-chain setting `key`, as returned by `get_settings`.
+    pub fn set_setting(&self, key: &str, value: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.settings.insert(key.into(), value.into());
+    }
+
+    /// Queue a result for the next call to `summarize_block`. Once the queue
+    /// is empty, `summarize_block` succeeds with an empty summary.
+    pub fn push_summarize_result(&self, result: Result<Vec<u8>, Error>) {
+        let mut state = self.state.lock().unwrap();
+        state.summarize_results.push_back(result);
+    }
+
+    /// Queue a result for the next call to `finalize_block`. Once the queue is
+    /// empty, `finalize_block` succeeds with a generated block id.
+    pub fn push_finalize_result(&self, result: Result<BlockId, Error>) {
+        let mut state = self.state.lock().unwrap();
+        state.finalize_results.push_back(result);
+    }
+
+    /// Make the next call to `get_settings` fail with `err`.
+    pub fn push_settings_error(&self, err: Error) {
+        let mut state = self.state.lock().unwrap();
+        state.settings_errors.push_back(err);
+    }
+
+    /// All calls recorded so far, in order.
+    pub fn calls(&self) -> Vec<ServiceCall> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    /// Recorded calls, excluding the read-only queries the engine makes while
+    /// deciding what to do.
+    pub fn actions(&self) -> Vec<ServiceCall> {
+        self.calls()
+            .into_iter()
+            .filter(|call| {
+                !matches!(
+                    *call,
+                    ServiceCall::GetBlocks(_)
+                        | ServiceCall::GetChainHead
+                        | ServiceCall::GetSettings(..)
+                        | ServiceCall::GetState(..)
+                )
+            })
+            .collect()
+    }
+
+    fn record(&self, call: ServiceCall) {
+        self.state.lock().unwrap().calls.push(call);
+    }
+}
+
+impl Service for MockService {
+    fn send_to(
+        &mut self,
+        peer: &PeerId,
+        message_type: &str,
+        payload: Vec<u8>,
+    ) -> Result<(), Error> {
+        self.record(ServiceCall::SendTo(
+            peer.clone(),
+            message_type.into(),
+            payload,
+        ));
+        Ok(())
+    }
+
+    fn broadcast(&mut self, message_type: &str, payload: Vec<u8>) -> Result<(), Error> {
+        self.record(ServiceCall::Broadcast(message_type.into(), payload));
+        Ok(())
+    }
+
+    fn initialize_block(&mut self, previous_id: Option<BlockId>) -> Result<(), Error> {
+        self.record(ServiceCall::InitializeBlock(previous_id));
+        Ok(())
+    }
+
+    fn summarize_block(&mut self) -> Result<Vec<u8>, Error> {
+        self.record(ServiceCall::SummarizeBlock);
+        let mut state = self.state.lock().unwrap();
+        state
+            .summarize_results
+            .pop_front()
+            .unwrap_or_else(|| Ok(vec![]))
+    }
+
+    fn finalize_block(&mut self, data: Vec<u8>) -> Result<BlockId, Error> {
+        self.record(ServiceCall::FinalizeBlock(data));
+        let mut state = self.state.lock().unwrap();
+        match state.finalize_results.pop_front() {
+            Some(result) => result,
+            None => {
+                state.published += 1;
+                Ok(format!("published-{}", state.published).into_bytes())
+            }
+        }
+    }
+
+    fn cancel_block(&mut self) -> Result<(), Error> {
+        self.record(ServiceCall::CancelBlock);
+        Ok(())
+    }
+
+    fn check_blocks(&mut self, priority: Vec<BlockId>) -> Result<(), Error> {
+        self.record(ServiceCall::CheckBlocks(priority));
+        Ok(())
+    }
+
+    fn commit_block(&mut self, block_id: BlockId) -> Result<(), Error> {
+        self.record(ServiceCall::CommitBlock(block_id));
+        Ok(())
+    }
+
+    fn ignore_block(&mut self, block_id: BlockId) -> Result<(), Error> {
+        self.record(ServiceCall::IgnoreBlock(block_id));
+        Ok(())
+    }
+
+    fn fail_block(&mut self, block_id: BlockId) -> Result<(), Error> {
+        self.record(ServiceCall::FailBlock(block_id));
+        Ok(())
+    }
+
+    fn get_blocks(&mut self, block_ids: Vec<BlockId>) -> Result<HashMap<BlockId, Block>, Error> {
+        self.record(ServiceCall::GetBlocks(block_ids.clone()));
+        let state = self.state.lock().unwrap();
+        Ok(block_ids
+            .into_iter()
+            .filter_map(|id| state.blocks.get(&id).map(|block| (id, block.clone())))
+            .collect())
+    }
+
+    fn get_chain_head(&mut self) -> Result<Block, Error> {
+        self.record(ServiceCall::GetChainHead);
+        let state = self.state.lock().unwrap();
+        state
+            .chain_head
+            .as_ref()
+            .and_then(|id| state.blocks.get(id))
+            .cloned()
+            .ok_or(Error::NoChainHead)
+    }
+
+    fn get_settings(
+        &mut self,
+        block_id: BlockId,
+        keys: Vec<String>,
+    ) -> Result<HashMap<String, String>, Error> {
+        self.record(ServiceCall::GetSettings(block_id, keys.clone()));
+        let mut state = self.state.lock().unwrap();
+        if let Some(err) = state.settings_errors.pop_front() {
+            return Err(err);
+        }
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| state.settings.get(&key).map(|value| (key, value.clone())))
+            .collect())
+    }
+
+    fn get_state(
+        &mut self,
+        block_id: BlockId,
+        addresses: Vec<String>,
+    ) -> Result<HashMap<String, Vec<u8>>, Error> {
+        self.record(ServiceCall::GetState(block_id, addresses));
+        Ok(HashMap::new())
+    }
+}
+
+/// Build a block whose consensus payload is valid for devmode.
+pub fn block(id: &str, previous: &str, block_num: u64) -> Block {
+    Block {
+        block_id: id.as_bytes().to_vec(),
+        previous_id: previous.as_bytes().to_vec(),
+        signer_id: b"signer".to_vec(),
+        block_num,
+        payload: b"Devmode".to_vec(),
+        summary: vec![],
+    }
+}
+
+/// Build the startup state the validator would hand the engine.
+pub fn startup_state(chain_head: Block) -> StartupState {
+    StartupState {
+        chain_head,
+        peers: vec![],
+        local_peer_info: PeerInfo {
+            peer_id: b"local".to_vec(),
+        },
+    }
+}
+
+/// Run `engine` against `service` until it has handled every update in
+/// `updates`, followed by a `Shutdown`.
+pub fn run_updates<E: Engine>(
+    engine: &mut E,
+    service: &MockService,
+    startup_state: StartupState,
+    updates: Vec<Update>,
+) -> Result<(), Error> {
+    let (sender, receiver) = channel();
+    for update in updates {
+        sender.send(update).unwrap();
+    }
+    sender.send(Update::Shutdown).unwrap();
+
+    engine.start(receiver, Box::new(service.clone()), startup_state)
+}
@@ -0,0 +1,75 @@
+/*
+ * Copyright 2018 Intel Corporation
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ * ------------------------------------------------------------------------------
+ */
+
+use std::collections::HashMap;
+
+use sawtooth_sdk::consensus::engine::{BlockId, PeerId};
+
+/// Keeps track of the blocks the engine has asked the validator to check,
+/// and of how many blocks from each signer the validator found invalid.
+pub struct ValidationTracker {
+    checking: HashMap<BlockId, PeerId>,
+    invalid_counts: HashMap<PeerId, u64>,
+}
+
+impl ValidationTracker {
+    pub fn new() -> Self {
+        ValidationTracker {
+            checking: HashMap::new(),
+            invalid_counts: HashMap::new(),
+        }
+    }
+
+    pub fn checking(&mut self, block_id: BlockId, signer_id: PeerId) {
+        self.checking.insert(block_id, signer_id);
+    }
+
+    pub fn valid(&mut self, block_id: &BlockId) {
+        self.checking.remove(block_id);
+    }
+
+    /// Stop tracking a block the validator found invalid. Returns its signer
+    /// and how many of that signer's blocks have been invalid, or None if the
+    /// block wasn't being checked.
+    pub fn invalid(&mut self, block_id: &BlockId) -> Option<(PeerId, u64)> {
+        let signer_id = self.checking.remove(block_id)?;
+        let count = self.invalid_counts.entry(signer_id.clone()).or_insert(0);
+        *count += 1;
+        Some((signer_id, *count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_invalid_blocks_per_signer() {
+        let mut tracker = ValidationTracker::new();
+        tracker.checking(b"b1".to_vec(), b"a".to_vec());
+        tracker.checking(b"b2".to_vec(), b"a".to_vec());
+        tracker.checking(b"b3".to_vec(), b"a".to_vec());
+        tracker.checking(b"c1".to_vec(), b"c".to_vec());
+
+        tracker.valid(&b"b1".to_vec());
+        assert_eq!(tracker.invalid(&b"b1".to_vec()), None);
+        assert_eq!(tracker.invalid(&b"b2".to_vec()), Some((b"a".to_vec(), 1)));
+        assert_eq!(tracker.invalid(&b"b3".to_vec()), Some((b"a".to_vec(), 2)));
+        assert_eq!(tracker.invalid(&b"c1".to_vec()), Some((b"c".to_vec(), 1)));
+        assert_eq!(tracker.invalid(&b"c1".to_vec()), None);
+    }
+}